@@ -0,0 +1,170 @@
+use core::{mem::size_of, ops::{Index, IndexMut}};
+use std::sync::Arc;
+
+use cl_sys::clSVMFree;
+
+use crate::{allocation_tracker, is_allocation_base, retire_allocation, BufferTracker, Device, Kernel, KernelCreationFailure, KernelLaunchFailure, MemoryRef, OCLFailure, SyncPointFailure, Token, OCL_SHARED_CONTEXT};
+
+pub struct ChunkedBuffer<T> {
+    chunks: Vec<MemoryRef<T>>,
+    // kept so element access skips the allocation lookup
+    trackers: Vec<Arc<BufferTracker>>,
+    chunk_len: usize,
+    len: usize
+}
+impl<T> ChunkedBuffer<T> {
+    pub fn from_chunks(chunks: Vec<MemoryRef<T>>) -> ChunkedBuffer<T> {
+        assert!(!chunks.is_empty(), "Chunk list cannot be empty");
+        let chunk_len = chunks[0].len();
+        let last = chunks.len() - 1;
+        for (ix, chunk) in chunks.iter().enumerate() {
+            assert!(is_allocation_base(chunk.ptr), "Chunk {} is not a live allocation", ix);
+            let okay = if ix == last { chunk.len() <= chunk_len } else { chunk.len() == chunk_len };
+            assert!(okay, "Chunk {} has unexpected length {}", ix, chunk.len());
+        }
+        let len = chunks.iter().map(|c| c.len()).sum();
+        ChunkedBuffer {
+            trackers: chunk_trackers(&chunks),
+            chunks: chunks,
+            chunk_len: chunk_len,
+            len: len
+        }
+    }
+    pub fn len(&self) -> usize { self.len }
+    pub fn chunk_len(&self) -> usize { self.chunk_len }
+    pub fn chunks(&self) -> &[MemoryRef<T>] { &self.chunks }
+    pub fn get(&self, ix: usize) -> Option<&T> {
+        if ix >= self.len { return None }
+        Some(&self[ix])
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.chunks.iter().flat_map(|c| c.as_items().iter())
+    }
+}
+impl<T> Index<usize> for ChunkedBuffer<T> {
+    type Output = T;
+    fn index(&self, ix: usize) -> &T {
+        assert!(ix < self.len, "Index {} is out of buffer of length {}", ix, self.len);
+        let chunk = ix / self.chunk_len;
+        self.trackers[chunk].await_host_access(false);
+        unsafe { &*self.chunks[chunk].ptr.cast::<T>().add(ix % self.chunk_len) }
+    }
+}
+impl<T> IndexMut<usize> for ChunkedBuffer<T> {
+    fn index_mut(&mut self, ix: usize) -> &mut T {
+        assert!(ix < self.len, "Index {} is out of buffer of length {}", ix, self.len);
+        let chunk = ix / self.chunk_len;
+        self.trackers[chunk].await_host_access(true);
+        unsafe { &mut *self.chunks[chunk].ptr.cast::<T>().add(ix % self.chunk_len) }
+    }
+}
+fn chunk_trackers<T>(chunks: &[MemoryRef<T>]) -> Vec<Arc<BufferTracker>> {
+    chunks.iter().map(|c| allocation_tracker(c.ptr).unwrap()).collect()
+}
+// Chunks are bound through their allocation leases, so pending
+// per-chunk launches finish before the memory goes away
+impl<T> Drop for ChunkedBuffer<T> {
+    fn drop(&mut self) { unsafe {
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        for chunk in &self.chunks {
+            // a chunk freed through a copy of its MemoryRef is left alone
+            if retire_allocation(chunk.ptr) {
+                clSVMFree(ctx, chunk.ptr);
+            }
+        }
+    } }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ChunkedLaunchFailure {
+    Creation(KernelCreationFailure),
+    Launch(KernelLaunchFailure)
+}
+
+impl Device {
+    pub fn allocate_chunked_buffer<T>(&self, count: usize) -> Result<ChunkedBuffer<T>, OCLFailure> {
+        assert!(count > 0, "Item count cannot be zero");
+        let chunk_len = (self.ext.props.max_alloc_size_in_bytes / size_of::<T>()).max(1);
+        let mut chunks = Vec::new();
+        let mut left = count;
+        while left != 0 {
+            let len = left.min(chunk_len);
+            match self.allocate_buffer::<T>(len) {
+                Ok(chunk) => chunks.push(chunk),
+                Err(err) => {
+                    for chunk in chunks { self.deallocate_memory(chunk) }
+                    return Err(err);
+                }
+            }
+            left -= len;
+        }
+        let val = ChunkedBuffer {
+            trackers: chunk_trackers(&chunks),
+            chunks: chunks,
+            chunk_len: chunk_len,
+            len: count
+        };
+        return Ok(val);
+    }
+    pub fn launch_chunked<T>(
+        &self,
+        buffer: &ChunkedBuffer<T>,
+        mut instantiate: impl FnMut(MemoryRef<T>, u64) -> Result<Kernel, KernelCreationFailure>,
+        dependencies: &[&Token]
+    ) -> Result<Token, ChunkedLaunchFailure> {
+        let mut tokens = Vec::new();
+        tokens.reserve(buffer.chunks.len());
+        let mut base = 0u64;
+        for chunk in &buffer.chunks {
+            let kernel = match instantiate(*chunk, base) {
+                Ok(kernel) => kernel,
+                Err(err) => return Err(ChunkedLaunchFailure::Creation(err)),
+            };
+            let tok = match self.launch_kernel(kernel, (chunk.len(),), dependencies) {
+                Ok(tok) => tok,
+                Err(err) => return Err(ChunkedLaunchFailure::Launch(err)),
+            };
+            tokens.push(tok);
+            base += chunk.len() as u64;
+        }
+        let tokens = tokens.iter().collect::<Vec<_>>();
+        match self.enqueue_marker(&tokens) {
+            Ok(joined) => return Ok(joined),
+            Err(SyncPointFailure::ResourcesExhausted) => {
+                return Err(ChunkedLaunchFailure::Launch(KernelLaunchFailure::NoMem))
+            },
+            Err(SyncPointFailure::InvalidDependencies) => {
+                return Err(ChunkedLaunchFailure::Launch(KernelLaunchFailure::InvalidArgs))
+            },
+        }
+    }
+}
+
+#[test]
+fn chunked_launch() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let item_count = 100_000;
+    let mut buffer = dev.allocate_chunked_buffer::<u64>(item_count).unwrap();
+    for ix in 0 .. item_count {
+        buffer[ix] = 0;
+    }
+
+    let text = r#"
+    __kernel void lol(__global ulong* chunk, ulong base) {
+        size_t gix = get_global_id(0);
+        chunk[gix] = base + gix;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let tok = dev.launch_chunked(&buffer, |chunk, base| {
+        bundle.instantiate_kernel("lol", (chunk, base,))
+    }, &[]).unwrap();
+    tok.await_completion().unwrap();
+
+    for (ix, item) in buffer.iter().enumerate() {
+        assert!(*item == ix as u64);
+    }
+}
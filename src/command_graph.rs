@@ -0,0 +1,559 @@
+use core::{mem::{size_of, transmute}, ptr::{null, null_mut}, sync::atomic::{AtomicU32, Ordering}};
+
+use cl_sys::{c_void, clEnqueueSVMMemcpy, clGetExtensionFunctionAddressForPlatform, cl_command_queue, cl_event, cl_int, cl_kernel, cl_uint, size_t, CL_FALSE, CL_SUCCESS};
+
+use crate::{allocation_tracker, enqueue_leased, queue::{extension_version, has_extension, make_version}, trace, Binding, CompletionAwaitFailure, EnqueueFailure, Device, GridDimmensions, Kernel, KernelLaunchFailure, MemoryRef, SyncPointFailure, Token, OCL_SHARED_CONTEXT};
+
+// cl_khr_command_buffer, missing from cl-sys. Entry points are looked up at runtime.
+#[allow(non_camel_case_types)]
+type cl_command_buffer_khr = *mut c_void;
+#[allow(non_camel_case_types)]
+type cl_sync_point_khr = cl_uint;
+type CreateCommandBufferFn = unsafe extern "system" fn(
+    cl_uint, *const cl_command_queue, *const u64, *mut cl_int
+) -> cl_command_buffer_khr;
+type CommandBufferOpFn = unsafe extern "system" fn(cl_command_buffer_khr) -> cl_int;
+type CommandNDRangeKernelFn = unsafe extern "system" fn(
+    cl_command_buffer_khr, cl_command_queue, *const c_void, cl_kernel, cl_uint,
+    *const size_t, *const size_t, *const size_t,
+    cl_uint, *const cl_sync_point_khr, *mut cl_sync_point_khr, *mut c_void
+) -> cl_int;
+type EnqueueCommandBufferFn = unsafe extern "system" fn(
+    cl_uint, *mut cl_command_queue, cl_command_buffer_khr, cl_uint, *const cl_event, *mut cl_event
+) -> cl_int;
+// The properties parameter came with revision 0.9.5 of the extension
+type CommandSVMMemcpyFn = unsafe extern "system" fn(
+    cl_command_buffer_khr, cl_command_queue, *mut c_void, *const c_void, size_t,
+    cl_uint, *const cl_sync_point_khr, *mut cl_sync_point_khr, *mut c_void
+) -> cl_int;
+type CommandSVMMemcpyWithPropertiesFn = unsafe extern "system" fn(
+    cl_command_buffer_khr, cl_command_queue, *const c_void, *mut c_void, *const c_void, size_t,
+    cl_uint, *const cl_sync_point_khr, *mut cl_sync_point_khr, *mut c_void
+) -> cl_int;
+const CL_INVALID_COMMAND_BUFFER_KHR: cl_int = -1138;
+
+static GRAPH_SERIAL: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub enum GraphFailure {
+    ResourcesExhausted,
+    ForeignNode(usize),
+    EmptyGraph,
+    LaunchFailed(usize, KernelLaunchFailure),
+    LengthMismatch,
+    NotALaunch,
+    // a buffer of the copy at this node was freed
+    BufferReleased(usize),
+    InvalidDependencies,
+    ReplayPending,
+    OverlappingCopy,
+    // an error code the driver is not documented to return
+    Rejected(i32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeId {
+    graph: u32,
+    index: u32
+}
+
+enum GraphCommand {
+    Launch {
+        kernel: Kernel,
+        grid_dim: u32,
+        dims: [size_t;3]
+    },
+    SvmCopy {
+        dst: *mut c_void,
+        src: *const c_void,
+        size: usize,
+        bindings: [Binding; 2]
+    }
+}
+impl GraphCommand {
+    fn bindings(&self) -> &[Binding] {
+        match self {
+            GraphCommand::Launch { kernel, .. } => &kernel.bindings,
+            GraphCommand::SvmCopy { bindings, .. } => bindings,
+        }
+    }
+    fn indirect_bindings(&self) -> &[Binding] {
+        match self {
+            GraphCommand::Launch { kernel, .. } => &kernel.indirect_bindings,
+            GraphCommand::SvmCopy { .. } => &[],
+        }
+    }
+}
+struct GraphNode {
+    command: GraphCommand,
+    deps: Vec<usize>
+}
+
+struct CommandBuffer {
+    handle: cl_command_buffer_khr,
+    queue: cl_command_queue,
+    release: CommandBufferOpFn,
+    enqueue: EnqueueCommandBufferFn
+}
+impl Drop for CommandBuffer {
+    fn drop(&mut self) {
+        let _ = unsafe { (self.release)(self.handle) };
+    }
+}
+enum NativeState {
+    Unbuilt,
+    Unavailable(cl_command_queue),
+    Built(CommandBuffer)
+}
+
+pub struct CommandGraph {
+    serial: u32,
+    nodes: Vec<GraphNode>,
+    native: NativeState,
+    last_replay: Option<Token>
+}
+// Kernels are only touched through `&mut self` or while launching,
+// copies hold leases on shared allocations
+unsafe impl Send for CommandGraph {}
+unsafe impl Sync for CommandGraph {}
+
+impl Default for CommandGraph {
+    fn default() -> Self {
+        CommandGraph::new()
+    }
+}
+impl CommandGraph {
+    pub fn new() -> CommandGraph {
+        CommandGraph {
+            serial: GRAPH_SERIAL.fetch_add(1, Ordering::Relaxed),
+            nodes: Vec::new(),
+            native: NativeState::Unbuilt,
+            last_replay: None
+        }
+    }
+    pub fn len(&self) -> usize { self.nodes.len() }
+    pub fn is_empty(&self) -> bool { self.nodes.is_empty() }
+    fn push_node(
+        &mut self,
+        command: GraphCommand,
+        dependencies: &[NodeId]
+    ) -> Result<NodeId, GraphFailure> {
+        let mut deps = Vec::new();
+        deps.reserve(dependencies.len());
+        for (ix, dep) in dependencies.iter().enumerate() {
+            if dep.graph != self.serial {
+                return Err(GraphFailure::ForeignNode(ix));
+            }
+            deps.push(dep.index as usize);
+        }
+        let id = NodeId {
+            graph: self.serial,
+            index: self.nodes.len() as u32
+        };
+        self.nodes.push(GraphNode { command: command, deps: deps });
+        self.native = NativeState::Unbuilt;
+        return Ok(id);
+    }
+    pub fn add_launch(
+        &mut self,
+        kernel: Kernel,
+        grid_dimmensions: impl GridDimmensions,
+        dependencies: &[NodeId]
+    ) -> Result<NodeId, GraphFailure> {
+        let command = GraphCommand::Launch {
+            kernel: kernel,
+            grid_dim: grid_dimmensions.dims(),
+            dims: grid_dimmensions.as_components()
+        };
+        self.push_node(command, dependencies)
+    }
+    pub fn add_svm_copy<T>(
+        &mut self,
+        src: MemoryRef<T>,
+        dst: MemoryRef<T>,
+        dependencies: &[NodeId]
+    ) -> Result<NodeId, GraphFailure> {
+        if src.len() != dst.len() {
+            return Err(GraphFailure::LengthMismatch);
+        }
+        let size = src.len() * size_of::<T>();
+        if copies_overlap(src.ptr, dst.ptr, size) {
+            return Err(GraphFailure::OverlappingCopy);
+        }
+        let (Some(src_tracker), Some(dst_tracker)) = (allocation_tracker(src.ptr), allocation_tracker(dst.ptr)) else {
+            return Err(GraphFailure::BufferReleased(self.nodes.len()));
+        };
+        let command = GraphCommand::SvmCopy {
+            dst: dst.ptr,
+            src: src.ptr,
+            size: size,
+            bindings: [Binding::new(src_tracker, false), Binding::new(dst_tracker, true)]
+        };
+        self.push_node(command, dependencies)
+    }
+    pub fn kernel_mut(&mut self, node: NodeId) -> Result<&mut Kernel, GraphFailure> {
+        if node.graph != self.serial {
+            return Err(GraphFailure::ForeignNode(0));
+        }
+        match &mut self.nodes[node.index as usize].command {
+            GraphCommand::Launch { kernel, .. } => {
+                // recorded commands captured the old arguments
+                self.native = NativeState::Unbuilt;
+                return Ok(kernel);
+            },
+            GraphCommand::SvmCopy { .. } => return Err(GraphFailure::NotALaunch),
+        }
+    }
+    // Node holding a binding freed since it was made
+    fn released_node(&self) -> Option<usize> {
+        self.nodes.iter().position(|node| {
+            node.command.bindings().iter().chain(node.command.indirect_bindings()).any(|b| b.is_stale())
+        })
+    }
+    fn released_failure(&self) -> GraphFailure {
+        match self.released_node() {
+            Some(ix) => match &self.nodes[ix].command {
+                GraphCommand::Launch { .. } => GraphFailure::LaunchFailed(ix, KernelLaunchFailure::ArgumentReleased),
+                GraphCommand::SvmCopy { .. } => GraphFailure::BufferReleased(ix),
+            },
+            None => GraphFailure::BufferReleased(0),
+        }
+    }
+    fn build_command_buffer(&self, device: &Device) -> Option<CommandBuffer> { unsafe {
+        if !has_extension(device.ext.handle, "cl_khr_command_buffer") {
+            return None;
+        }
+        let platform = OCL_SHARED_CONTEXT.platform.as_ref()?.handle;
+        let lookup = |name: &str| {
+            let ptr = clGetExtensionFunctionAddressForPlatform(platform, name.as_ptr().cast());
+            if ptr.is_null() { None } else { Some(ptr) }
+        };
+        let create: CreateCommandBufferFn = transmute(lookup("clCreateCommandBufferKHR\0")?);
+        let finalize: CommandBufferOpFn = transmute(lookup("clFinalizeCommandBufferKHR\0")?);
+        let release: CommandBufferOpFn = transmute(lookup("clReleaseCommandBufferKHR\0")?);
+        let command_ndrange: CommandNDRangeKernelFn = transmute(lookup("clCommandNDRangeKernelKHR\0")?);
+        let enqueue: EnqueueCommandBufferFn = transmute(lookup("clEnqueueCommandBufferKHR\0")?);
+        let copies = self.nodes.iter().any(|n| matches!(n.command, GraphCommand::SvmCopy { .. }));
+        let command_svm_memcpy = if copies {
+            let version = extension_version(device.ext.handle, "cl_khr_command_buffer")?;
+            let entry = lookup("clCommandSVMMemcpyKHR\0")?;
+            if version >= make_version(0, 9, 5) {
+                Some(Ok(transmute::<_, CommandSVMMemcpyWithPropertiesFn>(entry)))
+            } else {
+                Some(Err(transmute::<_, CommandSVMMemcpyFn>(entry)))
+            }
+        } else {
+            None
+        };
+
+        let queue = device.ext.command_queue;
+        let mut ret_code = CL_SUCCESS;
+        let handle = create(1, &queue, null(), &mut ret_code);
+        if ret_code != CL_SUCCESS {
+            return None;
+        }
+        let buffer = CommandBuffer {
+            handle: handle,
+            queue: queue,
+            release: release,
+            enqueue: enqueue
+        };
+        let mut sync_points = Vec::new();
+        sync_points.reserve(self.nodes.len());
+        for node in &self.nodes {
+            let waits = node.deps.iter().map(|d| sync_points[*d]).collect::<Vec<cl_sync_point_khr>>();
+            let (waits_ptr, waits_num) = if waits.is_empty() {
+                (null(), 0)
+            } else {
+                (waits.as_ptr(), waits.len() as u32)
+            };
+            let mut sync_point = 0;
+            let ret_code = match (&node.command, command_svm_memcpy) {
+                (GraphCommand::Launch { kernel, grid_dim, dims }, _) => command_ndrange(
+                    handle,
+                    null_mut(),
+                    null(),
+                    kernel.handle,
+                    *grid_dim,
+                    null(),
+                    dims.as_ptr(),
+                    null(),
+                    waits_num,
+                    waits_ptr,
+                    &mut sync_point,
+                    null_mut()
+                ),
+                (GraphCommand::SvmCopy { dst, src, size, .. }, Some(Ok(memcpy))) => memcpy(
+                    handle,
+                    null_mut(),
+                    null(),
+                    *dst,
+                    *src,
+                    *size,
+                    waits_num,
+                    waits_ptr,
+                    &mut sync_point,
+                    null_mut()
+                ),
+                (GraphCommand::SvmCopy { dst, src, size, .. }, Some(Err(memcpy))) => memcpy(
+                    handle,
+                    null_mut(),
+                    *dst,
+                    *src,
+                    *size,
+                    waits_num,
+                    waits_ptr,
+                    &mut sync_point,
+                    null_mut()
+                ),
+                (GraphCommand::SvmCopy { .. }, None) => unreachable!(),
+            };
+            if ret_code != CL_SUCCESS {
+                return None;
+            }
+            sync_points.push(sync_point);
+        }
+        if finalize(handle) != CL_SUCCESS {
+            return None;
+        }
+        return Some(buffer);
+    } }
+}
+
+pub(crate) fn copies_overlap(src: *const c_void, dst: *const c_void, size: usize) -> bool {
+    let (src, dst) = (src as usize, dst as usize);
+    size != 0 && src < dst + size && dst < src + size
+}
+pub(crate) fn enqueue_svm_copy(
+    queue: cl_command_queue,
+    dst: *mut c_void,
+    src: *const c_void,
+    size: usize,
+    bindings: &[Binding],
+    dependencies: &[&Token]
+) -> Result<Token, EnqueueFailure> {
+    enqueue_leased(bindings.iter(), dependencies, |deps, event| unsafe {
+        let (deps_ptr, deps_num) = if deps.is_empty() {
+            (null(), 0)
+        } else {
+            (deps.as_ptr(), deps.len() as u32)
+        };
+        let ret_code = clEnqueueSVMMemcpy(
+            queue,
+            CL_FALSE,
+            dst,
+            src,
+            size,
+            deps_num,
+            deps_ptr,
+            event
+        );
+        if ret_code == CL_SUCCESS {
+            trace::record_command(queue, *event, "svm_copy", "copy", deps);
+        }
+        ret_code
+    })
+}
+
+impl Device {
+    pub fn replay_graph(
+        &self,
+        graph: &mut CommandGraph,
+        dependencies: &[&Token]
+    ) -> Result<Token, GraphFailure> {
+        if graph.nodes.is_empty() {
+            return Err(GraphFailure::EmptyGraph);
+        }
+        // the command buffer path would not go through the launch checks
+        let lent = graph.nodes.iter().position(|node| match &node.command {
+            GraphCommand::Launch { kernel, .. } => kernel.lent_trackers().next().is_some(),
+            GraphCommand::SvmCopy { .. } => false,
+        });
+        if let Some(ix) = lent {
+            return Err(GraphFailure::LaunchFailed(ix, KernelLaunchFailure::OutOfScope));
+        }
+        let queue = self.ext.command_queue;
+        let rebuild = match &graph.native {
+            NativeState::Built(buffer) => buffer.queue != queue,
+            NativeState::Unavailable(failed) => *failed != queue,
+            NativeState::Unbuilt => true,
+        };
+        if rebuild {
+            graph.native = match graph.build_command_buffer(self) {
+                Some(buffer) => NativeState::Built(buffer),
+                None => NativeState::Unavailable(queue),
+            };
+        }
+        let done = match &graph.native {
+            NativeState::Built(buffer) => {
+                // A command buffer cannot be enqueued again while pending
+                if let Some(previous) = &graph.last_replay {
+                    match previous.await_completion() {
+                        Err(CompletionAwaitFailure::NoMem) => return Err(GraphFailure::ResourcesExhausted),
+                        _ => (),
+                    }
+                }
+                self.enqueue_command_buffer(graph, buffer, dependencies)?
+            },
+            _ => {
+                let mut entry = dependencies.to_vec();
+                if let Some(previous) = &graph.last_replay {
+                    entry.push(previous);
+                }
+                self.enqueue_graph_nodes(graph, &entry)?
+            },
+        };
+        graph.last_replay = Some(done.retained());
+        return Ok(done);
+    }
+    fn enqueue_command_buffer(
+        &self,
+        graph: &CommandGraph,
+        buffer: &CommandBuffer,
+        dependencies: &[&Token]
+    ) -> Result<Token, GraphFailure> { unsafe {
+        let bindings = graph.nodes.iter().flat_map(|node| {
+            node.command.bindings().iter().chain(node.command.indirect_bindings())
+        });
+        let outcome = enqueue_leased(bindings, dependencies, |deps, event| {
+            let (deps_ptr, deps_num) = if deps.is_empty() {
+                (null(), 0)
+            } else {
+                (deps.as_ptr(), deps.len() as u32)
+            };
+            (buffer.enqueue)(0, null_mut(), buffer.handle, deps_num, deps_ptr, event)
+        });
+        match outcome {
+            Ok(tok) => return Ok(tok),
+            Err(EnqueueFailure::Released) => return Err(graph.released_failure()),
+            Err(EnqueueFailure::ResourcesExhausted) |
+            Err(EnqueueFailure::Rejected(cl_sys::CL_OUT_OF_RESOURCES)) |
+            Err(EnqueueFailure::Rejected(cl_sys::CL_OUT_OF_HOST_MEMORY)) => {
+                return Err(GraphFailure::ResourcesExhausted)
+            },
+            Err(EnqueueFailure::Rejected(cl_sys::CL_INVALID_EVENT_WAIT_LIST)) => {
+                return Err(GraphFailure::InvalidDependencies)
+            },
+            Err(EnqueueFailure::Rejected(cl_sys::CL_INVALID_OPERATION)) => {
+                return Err(GraphFailure::ReplayPending)
+            },
+            Err(EnqueueFailure::Rejected(CL_INVALID_COMMAND_BUFFER_KHR)) |
+            Err(EnqueueFailure::Rejected(cl_sys::CL_INVALID_COMMAND_QUEUE)) |
+            Err(EnqueueFailure::Rejected(cl_sys::CL_INVALID_CONTEXT)) => unreachable!(),
+            Err(EnqueueFailure::Rejected(ret_code)) => return Err(GraphFailure::Rejected(ret_code)),
+        }
+    } }
+    fn enqueue_graph_nodes(
+        &self,
+        graph: &CommandGraph,
+        dependencies: &[&Token]
+    ) -> Result<Token, GraphFailure> {
+        let queue = self.ext.command_queue;
+        let mut tokens: Vec<Token> = Vec::new();
+        tokens.reserve(graph.nodes.len());
+        for (ix, node) in graph.nodes.iter().enumerate() {
+            let mut deps = node.deps.iter().map(|d| &tokens[*d]).collect::<Vec<_>>();
+            if deps.is_empty() {
+                deps.extend_from_slice(dependencies);
+            }
+            let tok = match &node.command {
+                GraphCommand::Launch { kernel, grid_dim, dims } => {
+                    match self.enqueue_kernel_ref(queue, kernel, *grid_dim, *dims, &deps) {
+                        Ok(tok) => tok,
+                        Err(err) => return Err(GraphFailure::LaunchFailed(ix, err)),
+                    }
+                },
+                GraphCommand::SvmCopy { dst, src, size, bindings } => {
+                    match enqueue_svm_copy(queue, *dst, *src, *size, bindings, &deps) {
+                        Ok(tok) => tok,
+                        Err(EnqueueFailure::Released) => return Err(GraphFailure::BufferReleased(ix)),
+                        Err(EnqueueFailure::ResourcesExhausted) |
+                        Err(EnqueueFailure::Rejected(cl_sys::CL_OUT_OF_RESOURCES)) |
+                        Err(EnqueueFailure::Rejected(cl_sys::CL_OUT_OF_HOST_MEMORY)) => {
+                            return Err(GraphFailure::ResourcesExhausted)
+                        },
+                        Err(EnqueueFailure::Rejected(cl_sys::CL_INVALID_EVENT_WAIT_LIST)) => {
+                            return Err(GraphFailure::InvalidDependencies)
+                        },
+                        Err(EnqueueFailure::Rejected(cl_sys::CL_MEM_COPY_OVERLAP)) => {
+                            return Err(GraphFailure::OverlappingCopy)
+                        },
+                        Err(EnqueueFailure::Rejected(ret_code)) => return Err(GraphFailure::Rejected(ret_code)),
+                    }
+                },
+            };
+            tokens.push(tok);
+        }
+        let all = tokens.iter().collect::<Vec<_>>();
+        match self.enqueue_marker(&all) {
+            Ok(tok) => return Ok(tok),
+            Err(SyncPointFailure::ResourcesExhausted) => return Err(GraphFailure::ResourcesExhausted),
+            Err(SyncPointFailure::InvalidDependencies) => return Err(GraphFailure::InvalidDependencies),
+        }
+    }
+}
+
+#[test]
+fn replayed_graph() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let item_count = 4096;
+    let first = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let second = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let copy = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let text = r#"
+    __kernel void fill(__global uint* param1, uint base) {
+        uint gix = get_global_id(0);
+        param1[gix] = base + gix;
+    }
+    __kernel void double_up(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+
+    let mut graph = CommandGraph::new();
+    let fill = graph.add_launch(bundle.instantiate_kernel("fill", (first, 0u32,)).unwrap(), (item_count,), &[]).unwrap();
+    let double = graph.add_launch(bundle.instantiate_kernel("double_up", (first,)).unwrap(), (item_count,), &[fill]).unwrap();
+    let copied = graph.add_svm_copy(first, copy, &[double]).unwrap();
+    assert!(matches!(graph.add_svm_copy(first, copy.slice(0 .. 1), &[]), Err(GraphFailure::LengthMismatch)));
+    assert!(matches!(graph.add_svm_copy(first.slice(0 .. 2), first.slice(1 .. 3), &[]), Err(GraphFailure::OverlappingCopy)));
+    assert!(matches!(graph.kernel_mut(copied), Err(GraphFailure::NotALaunch)));
+    let mut other = CommandGraph::new();
+    assert!(matches!(other.add_launch(bundle.instantiate_kernel("double_up", (first,)).unwrap(), (item_count,), &[fill]), Err(GraphFailure::ForeignNode(0))));
+    assert!(matches!(dev.replay_graph(&mut CommandGraph::new(), &[]), Err(GraphFailure::EmptyGraph)));
+
+    dev.replay_graph(&mut graph, &[]).unwrap().await_completion().unwrap();
+    for (ix, i) in copy.as_items().iter().enumerate() {
+        assert!(*i == ix as u32 * 2);
+    }
+
+    graph.kernel_mut(fill).unwrap().rebind((first, 1u32,)).unwrap();
+    graph.kernel_mut(double).unwrap().rebind((first,)).unwrap();
+    let tok1 = dev.replay_graph(&mut graph, &[]).unwrap();
+    let tok2 = dev.replay_graph(&mut graph, &[&tok1]).unwrap();
+    tok2.await_completion().unwrap();
+    for (ix, i) in copy.as_items().iter().enumerate() {
+        assert!(*i == (ix as u32 + 1) * 2);
+    }
+
+    // a graph of launches alone, replayed back to back
+    let mut launches = CommandGraph::new();
+    let fill = launches.add_launch(bundle.instantiate_kernel("fill", (second, 5u32,)).unwrap(), (item_count,), &[]).unwrap();
+    launches.add_launch(bundle.instantiate_kernel("double_up", (second,)).unwrap(), (item_count,), &[fill]).unwrap();
+    for _ in 0..3 {
+        dev.replay_graph(&mut launches, &[]).unwrap();
+    }
+    dev.replay_graph(&mut launches, &[]).unwrap().await_completion().unwrap();
+    for (ix, i) in second.as_items().iter().enumerate() {
+        assert!(*i == (ix as u32 + 5) * 2);
+    }
+    drop(graph);
+    drop(launches);
+    dev.deallocate_memory(first);
+    dev.deallocate_memory(second);
+    dev.deallocate_memory(copy);
+}
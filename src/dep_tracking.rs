@@ -0,0 +1,175 @@
+use core::{mem::size_of, ptr::{addr_of_mut, null_mut}, sync::atomic::{AtomicUsize, Ordering}};
+use std::sync::{Arc, MutexGuard};
+
+use cl_sys::{clGetEventInfo, cl_event, cl_int, CL_COMPLETE, CL_EVENT_COMMAND_EXECUTION_STATUS, CL_SUCCESS};
+
+use crate::{BufferTracker, Token};
+
+#[derive(Default)]
+pub(crate) struct AccessHistory {
+    writer: Option<Token>,
+    readers: Vec<Token>
+}
+
+fn has_finished(token: &Token) -> bool { unsafe {
+    let mut status: cl_int = 0;
+    let ret_code = clGetEventInfo(
+        token.0.token,
+        CL_EVENT_COMMAND_EXECUTION_STATUS,
+        size_of::<cl_int>(),
+        addr_of_mut!(status).cast(),
+        null_mut()
+    );
+    // failed commands report a negative status
+    ret_code == CL_SUCCESS && status <= CL_COMPLETE
+} }
+
+// Trackers with tracking on, lets lookups be skipped while there are none
+static TRACKING_COUNT: AtomicUsize = AtomicUsize::new(0);
+pub(crate) fn any_tracking() -> bool {
+    TRACKING_COUNT.load(Ordering::Acquire) != 0
+}
+
+impl BufferTracker {
+    pub(crate) fn set_tracking(&self, enabled: bool) {
+        let mut history = self.history.lock().unwrap();
+        if !enabled {
+            *history = AccessHistory::default();
+        }
+        let was_enabled = self.tracking.swap(enabled, Ordering::AcqRel);
+        match (was_enabled, enabled) {
+            (false, true) => { TRACKING_COUNT.fetch_add(1, Ordering::AcqRel); },
+            (true, false) => { TRACKING_COUNT.fetch_sub(1, Ordering::AcqRel); },
+            _ => (),
+        }
+    }
+    pub(crate) fn is_tracking(&self) -> bool {
+        self.tracking.load(Ordering::Acquire)
+    }
+    pub(crate) fn await_host_access(&self, exclusive: bool) {
+        if !self.is_tracking() {
+            return;
+        }
+        // waiting under the lock would stall launches on other threads
+        let mut pending = Vec::new();
+        {
+            let history = self.history.lock().unwrap();
+            pending.extend(history.writer.iter().map(|w| w.retained()));
+            if exclusive {
+                pending.extend(history.readers.iter().map(|r| r.retained()));
+            }
+        }
+        for token in pending {
+            let _ = token.await_completion();
+        }
+    }
+}
+
+impl Drop for BufferTracker {
+    fn drop(&mut self) {
+        if *self.tracking.get_mut() {
+            TRACKING_COUNT.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+pub(crate) struct TrackedAccesses<'a> {
+    locked: Vec<(MutexGuard<'a, AccessHistory>, bool)>
+}
+impl TrackedAccesses<'_> {
+    pub(crate) fn wait_list(&self) -> Vec<cl_event> {
+        let mut events = Vec::new();
+        for (history, writes) in &self.locked {
+            if let Some(writer) = &history.writer {
+                events.push(writer.0.token);
+            }
+            if *writes {
+                events.extend(history.readers.iter().map(|r| r.0.token));
+            }
+        }
+        return events;
+    }
+    pub(crate) fn record(&mut self, token: &Token) {
+        for (history, writes) in &mut self.locked {
+            if *writes {
+                history.writer = Some(token.retained());
+                history.readers.clear();
+            } else {
+                history.readers.retain(|r| !has_finished(r));
+                history.readers.push(token.retained());
+            }
+        }
+    }
+}
+
+pub(crate) fn lock_accesses<'a>(
+    accesses: impl Iterator<Item = (&'a Arc<BufferTracker>, bool)>
+) -> TrackedAccesses<'a> {
+    let mut tracked = accesses
+        .filter(|(tracker, _)| tracker.is_tracking())
+        .collect::<Vec<_>>();
+    // address order, so concurrent launches can not deadlock
+    tracked.sort_by_key(|(tracker, _)| Arc::as_ptr(tracker) as usize);
+    let mut merged: Vec<(&'a Arc<BufferTracker>, bool)> = Vec::new();
+    for (tracker, writes) in tracked {
+        match merged.last_mut() {
+            Some((last, last_writes)) if Arc::ptr_eq(last, tracker) => *last_writes |= writes,
+            _ => merged.push((tracker, writes)),
+        }
+    }
+    let locked = merged.into_iter()
+        .map(|(tracker, writes)| (tracker.history.lock().unwrap(), writes))
+        .collect();
+    TrackedAccesses { locked: locked }
+}
+
+#[test]
+fn implicit_ordering() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let item_count = 65535;
+    let mut src = crate::SvmVec::<u32>::with_capacity(item_count).unwrap();
+    src.resize(item_count, 0);
+    let mut dst = crate::SvmVec::<u32>::with_capacity(item_count).unwrap();
+    dst.resize(item_count, 0);
+    src.set_dependency_tracking(true);
+    dst.set_dependency_tracking(true);
+
+    let text = r#"
+    __kernel void fill(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] = gix;
+    }
+    __kernel void copy_over(const __global uint* param1, __global uint* param2) {
+        uint gix = get_global_id(0);
+        param2[gix] = param1[gix];
+    }
+    __kernel void double_up(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    // no tokens passed around, the wait lists come from the buffers
+    let _fill = dev.launch_kernel(bundle.instantiate_kernel("fill", (&src,)).unwrap(), (item_count,), &[]).unwrap();
+    let _copy = dev.launch_kernel(bundle.instantiate_kernel("copy_over", (&src, &dst,)).unwrap(), (item_count,), &[]).unwrap();
+    let _double = dev.launch_kernel(bundle.instantiate_kernel("double_up", (&src,)).unwrap(), (item_count,), &[]).unwrap();
+
+    for (ix, i) in dst.iter().enumerate() {
+        assert!(*i == ix as u32);
+    }
+    for (ix, i) in src.iter().enumerate() {
+        assert!(*i == ix as u32 * 2);
+    }
+
+    let mem = dev.allocate_buffer::<u32>(item_count).unwrap();
+    mem.set_dependency_tracking(true);
+    let _fill = dev.launch_kernel(bundle.instantiate_kernel("fill", (mem,)).unwrap(), (item_count,), &[]).unwrap();
+    let _double = dev.launch_kernel(bundle.instantiate_kernel("double_up", (mem,)).unwrap(), (item_count,), &[]).unwrap();
+    for (ix, i) in mem.as_items().iter().enumerate() {
+        assert!(*i == ix as u32 * 2);
+    }
+    dev.deallocate_memory(mem);
+}
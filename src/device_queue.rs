@@ -0,0 +1,198 @@
+use core::{any::TypeId, mem::{align_of_val, size_of, transmute}, ptr::{addr_of, addr_of_mut, drop_in_place, null_mut}, sync::atomic::Ordering};
+
+use cl_sys::{clGetCommandQueueInfo, clReleaseCommandQueue, clRetainCommandQueue, cl_command_queue, cl_device_id, cl_queue_properties, cl_uint, CL_QUEUE_ON_DEVICE, CL_QUEUE_ON_DEVICE_DEFAULT, CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE, CL_QUEUE_SIZE};
+
+use crate::{make_command_queue, va_args_emu::{self, ErasedRef}, Device, QueueCreationFailure, OCL_SHARED_CONTEXT};
+
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceQueueFailure {
+    ResourcesExhausted,
+    Unsupported,
+    SizeTooLarge,
+    InvalidSize
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceQueueProps {
+    pub max_queues: u32,
+    pub max_events: u32,
+    pub preferred_size: u32,
+    pub max_size: u32
+}
+
+pub struct DeviceQueue {
+    handle: cl_command_queue,
+    size: u32,
+    is_default: bool
+}
+// Queue handles are thread safe
+unsafe impl Send for DeviceQueue {}
+unsafe impl Sync for DeviceQueue {}
+impl DeviceQueue {
+    pub fn size(&self) -> u32 { self.size }
+    pub fn is_default(&self) -> bool { self.is_default }
+}
+impl Drop for DeviceQueue {
+    fn drop(&mut self) {
+        let _ = unsafe { clReleaseCommandQueue(self.handle) };
+    }
+}
+impl va_args_emu::KernelArgument for &DeviceQueue {
+    fn as_opaque(&self) -> ErasedRef {
+        ErasedRef {
+            data_ptr: addr_of!(self.handle).cast(),
+            size: size_of::<cl_command_queue>(),
+            alignment: align_of_val(&self.handle),
+            type_id: TypeId::of::<DeviceQueue>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: None
+        }
+    }
+}
+
+// Pre 2.0 devices reject the queries, which leaves all limits at zero
+pub(crate) fn query_device_queue_props(device: cl_device_id) -> Result<DeviceQueueProps, cl_sys::cl_int> { unsafe {
+    let unsupported = DeviceQueueProps {
+        max_queues: 0,
+        max_events: 0,
+        preferred_size: 0,
+        max_size: 0
+    };
+    let mut props = unsupported;
+    for (param, value) in [
+        (cl_sys::CL_DEVICE_MAX_ON_DEVICE_QUEUES, &mut props.max_queues),
+        (cl_sys::CL_DEVICE_MAX_ON_DEVICE_EVENTS, &mut props.max_events),
+        (cl_sys::CL_DEVICE_QUEUE_ON_DEVICE_PREFERRED_SIZE, &mut props.preferred_size),
+        (cl_sys::CL_DEVICE_QUEUE_ON_DEVICE_MAX_SIZE, &mut props.max_size),
+    ] {
+        let ret_code = cl_sys::clGetDeviceInfo(
+            device,
+            param,
+            size_of::<u32>(),
+            (value as *mut u32).cast(),
+            core::ptr::null_mut()
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => return Err(ret_code),
+            _ => return Ok(unsupported),
+        }
+    }
+    return Ok(props);
+} }
+
+impl Device {
+    fn retain_default_device_queue(&self, handle: cl_command_queue) { unsafe {
+        let held = self.ext.default_device_queue.compare_exchange(
+            null_mut(),
+            handle,
+            Ordering::AcqRel,
+            Ordering::Acquire
+        );
+        if held.is_ok() {
+            let _ = clRetainCommandQueue(handle);
+        }
+    } }
+    pub fn create_device_queue(
+        &self,
+        size: Option<u32>,
+        make_default: bool
+    ) -> Result<DeviceQueue, DeviceQueueFailure> { unsafe {
+        let caps = self.ext.props.device_queue_caps;
+        if caps.max_queues == 0 {
+            return Err(DeviceQueueFailure::Unsupported);
+        }
+        let size = size.unwrap_or(caps.preferred_size);
+        if size == 0 {
+            return Err(DeviceQueueFailure::InvalidSize);
+        }
+        if size > caps.max_size {
+            return Err(DeviceQueueFailure::SizeTooLarge);
+        }
+        let props =
+            CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE |
+            CL_QUEUE_ON_DEVICE |
+            if make_default { CL_QUEUE_ON_DEVICE_DEFAULT } else { 0 };
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        let sizing = [CL_QUEUE_SIZE as cl_queue_properties, size as cl_queue_properties];
+        let handle = match make_command_queue(ctx, self.ext.handle, props, &sizing) {
+            Ok((handle, _)) => handle,
+            Err(QueueCreationFailure::ResourcesExhausted) => return Err(DeviceQueueFailure::ResourcesExhausted),
+            Err(QueueCreationFailure::InvalidProperties) => return Err(DeviceQueueFailure::InvalidSize),
+        };
+        // Asking for a default queue once there is one returns the
+        // existing queue, whatever size was requested
+        let mut actual_size: cl_uint = 0;
+        let ret_code = clGetCommandQueueInfo(
+            handle,
+            CL_QUEUE_SIZE,
+            size_of::<cl_uint>(),
+            addr_of_mut!(actual_size).cast(),
+            null_mut()
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                let _ = clReleaseCommandQueue(handle);
+                return Err(DeviceQueueFailure::ResourcesExhausted)
+            },
+            _ => unreachable!()
+        }
+        // Kernels reach the default queue through get_default_queue(),
+        // so the device keeps it alive for as long as it can launch them
+        if make_default {
+            self.retain_default_device_queue(handle);
+        }
+        let val = DeviceQueue {
+            handle: handle,
+            size: actual_size,
+            is_default: make_default
+        };
+        return Ok(val);
+    } }
+}
+
+#[test]
+fn child_kernels() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+    if dev.get_properties().device_queue_caps.max_queues == 0 {
+        return;
+    }
+    let default_queue = dev.create_device_queue(None, true).unwrap();
+    assert!(default_queue.is_default());
+    let side_queue = dev.create_device_queue(None, false).unwrap();
+    assert!(matches!(dev.create_device_queue(Some(0), false), Err(DeviceQueueFailure::InvalidSize)));
+
+    let item_count = 1024;
+    let mut mem = dev.allocate_buffer::<u32>(item_count).unwrap();
+    for item in mem.as_mut_items() {
+        *item = 1;
+    }
+    let text = r#"
+    __kernel void parent(__global uint* param1, queue_t side, uint count) {
+        clk_event_t doubled;
+        enqueue_kernel(get_default_queue(), CLK_ENQUEUE_FLAGS_WAIT_KERNEL, ndrange_1D(count), 0, NULL, &doubled, ^{
+            param1[get_global_id(0)] *= 2;
+        });
+        enqueue_kernel(side, CLK_ENQUEUE_FLAGS_WAIT_KERNEL, ndrange_1D(count), 1, &doubled, NULL, ^{
+            param1[get_global_id(0)] += 1;
+        });
+        release_event(doubled);
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let kern = bundle.instantiate_kernel("parent", (mem, &side_queue, item_count as u32,)).unwrap();
+    drop(side_queue);
+    drop(default_queue);
+    let tok = dev.launch_kernel(kern, (1,), &[]).unwrap();
+    tok.await_completion().unwrap();
+
+    for i in mem.as_items() {
+        assert!(*i == 3);
+    }
+    dev.deallocate_memory(mem);
+}
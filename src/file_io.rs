@@ -0,0 +1,164 @@
+use core::{mem::size_of, ops::Range};
+use std::{fs::File, io::{Read, Seek, SeekFrom, Write}, os::fd::AsRawFd, path::Path};
+
+use crate::{Device, MemoryRef};
+
+#[derive(Debug, Clone, Copy)]
+pub enum FileTransferFailure {
+    Io(std::io::ErrorKind),
+    ResourcesExhausted,
+    MisalignedRange,
+    EmptyRange,
+    ZeroSizedItems
+}
+impl From<std::io::Error> for FileTransferFailure {
+    fn from(value: std::io::Error) -> Self {
+        FileTransferFailure::Io(value.kind())
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+// Items are taken verbatim, so only types valid for any bit pattern
+pub trait PlainData: Copy + sealed::Sealed {}
+macro_rules! plain_data {
+    ($($t:ty),*) => {
+        $(
+            impl sealed::Sealed for $t {}
+            impl PlainData for $t {}
+        )*
+    };
+}
+plain_data!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, ());
+impl<T: PlainData, const N: usize> sealed::Sealed for [T; N] {}
+impl<T: PlainData, const N: usize> PlainData for [T; N] {}
+
+const TRANSFER_BLOCK_SIZE: usize = 16 << 20;
+
+impl Device {
+    pub fn allocate_buffer_from_file<T: PlainData>(
+        &self,
+        path: impl AsRef<Path>,
+        range: Range<u64>,
+        mut progress: impl FnMut(u64, u64)
+    ) -> Result<Vec<MemoryRef<T>>, FileTransferFailure> {
+        if range.start >= range.end {
+            return Err(FileTransferFailure::EmptyRange);
+        }
+        let item_size = size_of::<T>() as u64;
+        if item_size == 0 {
+            return Err(FileTransferFailure::ZeroSizedItems);
+        }
+        let total = range.end - range.start;
+        if total % item_size != 0 {
+            return Err(FileTransferFailure::MisalignedRange);
+        }
+        let mut file = File::open(path)?;
+        if file.metadata()?.len() < range.end {
+            return Err(FileTransferFailure::Io(std::io::ErrorKind::UnexpectedEof));
+        }
+        unsafe {
+            let _ = libc::posix_fadvise(
+                file.as_raw_fd(),
+                range.start as _,
+                total as _,
+                libc::POSIX_FADV_SEQUENTIAL
+            );
+        }
+        file.seek(SeekFrom::Start(range.start))?;
+
+        let chunk_items = (self.ext.props.max_alloc_size_in_bytes as u64 / item_size).max(1);
+        let mut items_left = total / item_size;
+        let mut done = 0;
+        let mut chunks = Vec::new();
+        while items_left != 0 {
+            let count = items_left.min(chunk_items);
+            let mut chunk = match self.allocate_buffer::<T>(count as usize) {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    for chunk in chunks { self.deallocate_memory(chunk) }
+                    return Err(FileTransferFailure::ResourcesExhausted);
+                }
+            };
+            let bytes = unsafe {
+                core::slice::from_raw_parts_mut(
+                    chunk.as_mut_items().as_mut_ptr().cast::<u8>(),
+                    count as usize * item_size as usize
+                )
+            };
+            for block in bytes.chunks_mut(TRANSFER_BLOCK_SIZE) {
+                if let Err(err) = file.read_exact(block) {
+                    self.deallocate_memory(chunk);
+                    for chunk in chunks { self.deallocate_memory(chunk) }
+                    return Err(err.into());
+                }
+                done += block.len() as u64;
+                progress(done, total);
+            }
+            chunks.push(chunk);
+            items_left -= count;
+        }
+        return Ok(chunks);
+    }
+    pub fn write_to_file<T: Copy>(
+        &self,
+        path: impl AsRef<Path>,
+        chunks: &[MemoryRef<T>],
+        mut progress: impl FnMut(u64, u64)
+    ) -> Result<(), FileTransferFailure> {
+        let total = chunks.iter().map(|c| (c.len() * size_of::<T>()) as u64).sum();
+        let mut file = File::create(path)?;
+        let mut done = 0;
+        for chunk in chunks {
+            let bytes = unsafe {
+                core::slice::from_raw_parts(
+                    chunk.as_items().as_ptr().cast::<u8>(),
+                    chunk.len() * size_of::<T>()
+                )
+            };
+            for block in bytes.chunks(TRANSFER_BLOCK_SIZE) {
+                file.write_all(block)?;
+                done += block.len() as u64;
+                progress(done, total);
+            }
+        }
+        file.sync_data()?;
+        return Ok(());
+    }
+}
+
+#[test]
+fn file_roundtrip() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let path = std::env::temp_dir().join("rustly_cl_file_roundtrip");
+    let data = (0 .. 65536u32).collect::<Vec<_>>();
+    let bytes = unsafe {
+        core::slice::from_raw_parts(data.as_ptr().cast::<u8>(), data.len() * 4)
+    };
+    std::fs::write(&path, bytes).unwrap();
+
+    let mut last = 0;
+    let chunks = dev.allocate_buffer_from_file::<u32>(&path, 16 .. bytes.len() as u64, |done, total| {
+        assert!(done > last && done <= total);
+        last = done;
+    }).unwrap();
+    let empty = dev.allocate_buffer_from_file::<u32>(&path, 16 .. 16, |_, _| {});
+    assert!(matches!(empty, Err(FileTransferFailure::EmptyRange)));
+    let unit = dev.allocate_buffer_from_file::<()>(&path, 0 .. 16, |_, _| {});
+    assert!(matches!(unit, Err(FileTransferFailure::ZeroSizedItems)));
+    assert!(last == bytes.len() as u64 - 16);
+    let loaded = chunks.iter().flat_map(|c| c.as_items().iter().copied()).collect::<Vec<_>>();
+    assert!(loaded[..] == data[4..]);
+
+    dev.write_to_file(&path, &chunks, |_, _| {}).unwrap();
+    let written = std::fs::read(&path).unwrap();
+    assert!(written[..] == bytes[16..]);
+
+    for chunk in chunks {
+        dev.deallocate_memory(chunk);
+    }
+    let _ = std::fs::remove_file(&path);
+}
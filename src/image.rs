@@ -0,0 +1,615 @@
+use core::{any::TypeId, marker::PhantomData, mem::{align_of_val, size_of, size_of_val, transmute}, ptr::{addr_of, drop_in_place, null, null_mut}};
+
+use cl_sys::{c_void, clCreateImage, clCreateSamplerWithProperties, clEnqueueMapImage, clEnqueueReadImage, clEnqueueUnmapMemObject, clEnqueueWriteImage, clGetSupportedImageFormats, clReleaseEvent, clReleaseMemObject, clReleaseSampler, clWaitForEvents, cl_channel_order, cl_channel_type, cl_image_desc, cl_image_format, cl_mem, cl_mem_object_type, cl_sampler, cl_sampler_properties, CL_ADDRESS_CLAMP, CL_ADDRESS_CLAMP_TO_EDGE, CL_ADDRESS_MIRRORED_REPEAT, CL_ADDRESS_NONE, CL_ADDRESS_REPEAT, CL_BGRA, CL_FALSE, CL_FILTER_LINEAR, CL_FILTER_NEAREST, CL_FLOAT, CL_HALF_FLOAT, CL_MAP_READ, CL_MAP_WRITE, CL_MEM_OBJECT_IMAGE2D, CL_MEM_OBJECT_IMAGE2D_ARRAY, CL_MEM_OBJECT_IMAGE3D, CL_MEM_READ_WRITE, CL_R, CL_RG, CL_RGBA, CL_SAMPLER_ADDRESSING_MODE, CL_SAMPLER_FILTER_MODE, CL_SAMPLER_NORMALIZED_COORDS, CL_SIGNED_INT16, CL_SIGNED_INT32, CL_SIGNED_INT8, CL_SNORM_INT16, CL_SNORM_INT8, CL_SUCCESS, CL_TRUE, CL_UNORM_INT16, CL_UNORM_INT8, CL_UNSIGNED_INT16, CL_UNSIGNED_INT32, CL_UNSIGNED_INT8};
+
+use crate::{trace, va_args_emu::{self, ErasedRef}, Device, OCLFailure, OCL_SHARED_CONTEXT};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ImageFailure {
+    ResourcesExhausted,
+    UnsupportedFormat,
+    InvalidSize,
+    SizeMismatch,
+    Unsupported
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelOrder {
+    R, RG, RGBA, BGRA
+}
+impl ChannelOrder {
+    fn raw(self) -> cl_channel_order {
+        match self {
+            ChannelOrder::R => CL_R,
+            ChannelOrder::RG => CL_RG,
+            ChannelOrder::RGBA => CL_RGBA,
+            ChannelOrder::BGRA => CL_BGRA,
+        }
+    }
+    fn from_raw(raw: cl_channel_order) -> Option<Self> {
+        let val = match raw {
+            CL_R => ChannelOrder::R,
+            CL_RG => ChannelOrder::RG,
+            CL_RGBA => ChannelOrder::RGBA,
+            CL_BGRA => ChannelOrder::BGRA,
+            _ => return None
+        };
+        Some(val)
+    }
+    fn channel_count(self) -> usize {
+        match self {
+            ChannelOrder::R => 1,
+            ChannelOrder::RG => 2,
+            ChannelOrder::RGBA |
+            ChannelOrder::BGRA => 4,
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelType {
+    SnormInt8, SnormInt16, UnormInt8, UnormInt16,
+    SignedInt8, SignedInt16, SignedInt32,
+    UnsignedInt8, UnsignedInt16, UnsignedInt32,
+    HalfFloat, Float
+}
+impl ChannelType {
+    fn raw(self) -> cl_channel_type {
+        match self {
+            ChannelType::SnormInt8 => CL_SNORM_INT8,
+            ChannelType::SnormInt16 => CL_SNORM_INT16,
+            ChannelType::UnormInt8 => CL_UNORM_INT8,
+            ChannelType::UnormInt16 => CL_UNORM_INT16,
+            ChannelType::SignedInt8 => CL_SIGNED_INT8,
+            ChannelType::SignedInt16 => CL_SIGNED_INT16,
+            ChannelType::SignedInt32 => CL_SIGNED_INT32,
+            ChannelType::UnsignedInt8 => CL_UNSIGNED_INT8,
+            ChannelType::UnsignedInt16 => CL_UNSIGNED_INT16,
+            ChannelType::UnsignedInt32 => CL_UNSIGNED_INT32,
+            ChannelType::HalfFloat => CL_HALF_FLOAT,
+            ChannelType::Float => CL_FLOAT,
+        }
+    }
+    fn from_raw(raw: cl_channel_type) -> Option<Self> {
+        let val = match raw {
+            CL_SNORM_INT8 => ChannelType::SnormInt8,
+            CL_SNORM_INT16 => ChannelType::SnormInt16,
+            CL_UNORM_INT8 => ChannelType::UnormInt8,
+            CL_UNORM_INT16 => ChannelType::UnormInt16,
+            CL_SIGNED_INT8 => ChannelType::SignedInt8,
+            CL_SIGNED_INT16 => ChannelType::SignedInt16,
+            CL_SIGNED_INT32 => ChannelType::SignedInt32,
+            CL_UNSIGNED_INT8 => ChannelType::UnsignedInt8,
+            CL_UNSIGNED_INT16 => ChannelType::UnsignedInt16,
+            CL_UNSIGNED_INT32 => ChannelType::UnsignedInt32,
+            CL_HALF_FLOAT => ChannelType::HalfFloat,
+            CL_FLOAT => ChannelType::Float,
+            _ => return None
+        };
+        Some(val)
+    }
+    fn channel_size(self) -> usize {
+        match self {
+            ChannelType::SnormInt8 |
+            ChannelType::UnormInt8 |
+            ChannelType::SignedInt8 |
+            ChannelType::UnsignedInt8 => 1,
+            ChannelType::SnormInt16 |
+            ChannelType::UnormInt16 |
+            ChannelType::SignedInt16 |
+            ChannelType::UnsignedInt16 |
+            ChannelType::HalfFloat => 2,
+            ChannelType::SignedInt32 |
+            ChannelType::UnsignedInt32 |
+            ChannelType::Float => 4,
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageFormat {
+    pub order: ChannelOrder,
+    pub data_type: ChannelType
+}
+impl ImageFormat {
+    pub fn pixel_size(&self) -> usize {
+        self.order.channel_count() * self.data_type.channel_size()
+    }
+}
+
+struct ImageObject {
+    handle: cl_mem,
+    format: ImageFormat,
+    // width, height, depth or array length
+    extent: [usize;3]
+}
+// Memory object handles are thread safe
+unsafe impl Send for ImageObject {}
+unsafe impl Sync for ImageObject {}
+impl ImageObject {
+    fn byte_size(&self) -> usize {
+        self.format.pixel_size() * self.extent[0] * self.extent[1] * self.extent[2]
+    }
+}
+impl Drop for ImageObject {
+    fn drop(&mut self) {
+        let _ = unsafe { clReleaseMemObject(self.handle) };
+    }
+}
+
+macro_rules! image_kinds {
+    ($($kind:ident),+) => {
+      $(
+        impl $kind {
+            pub fn format(&self) -> ImageFormat { self.0.format }
+            pub fn write<T: Copy>(&self, device: &Device, data: &[T]) -> Result<(), ImageFailure> {
+                device.write_image_object(&self.0, data)
+            }
+            pub fn read<T: Copy>(&self, device: &Device, data: &mut [T]) -> Result<(), ImageFailure> {
+                device.read_image_object(&self.0, data)
+            }
+            pub fn map<'a>(&'a mut self, device: &'a Device) -> Result<ImageMapping<'a>, ImageFailure> {
+                device.map_image_object(&self.0)
+            }
+        }
+        impl va_args_emu::KernelArgument for &$kind {
+            fn as_opaque(&self) -> ErasedRef {
+                ErasedRef {
+                    data_ptr: addr_of!(self.0.handle).cast(),
+                    size: size_of::<cl_mem>(),
+                    alignment: align_of_val(&self.0.handle),
+                    type_id: TypeId::of::<$kind>(),
+                    dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+                    tracker: None
+                }
+            }
+        }
+      )+
+    };
+}
+pub struct Image2d(ImageObject);
+impl Image2d {
+    pub fn width(&self) -> usize { self.0.extent[0] }
+    pub fn height(&self) -> usize { self.0.extent[1] }
+}
+pub struct Image3d(ImageObject);
+impl Image3d {
+    pub fn width(&self) -> usize { self.0.extent[0] }
+    pub fn height(&self) -> usize { self.0.extent[1] }
+    pub fn depth(&self) -> usize { self.0.extent[2] }
+}
+pub struct Image2dArray(ImageObject);
+impl Image2dArray {
+    pub fn width(&self) -> usize { self.0.extent[0] }
+    pub fn height(&self) -> usize { self.0.extent[1] }
+    pub fn array_len(&self) -> usize { self.0.extent[2] }
+}
+image_kinds!(Image2d, Image3d, Image2dArray);
+
+pub struct ImageMapping<'a> {
+    device: &'a Device,
+    handle: cl_mem,
+    ptr: *mut c_void,
+    len: usize,
+    row_pitch: usize,
+    slice_pitch: usize,
+    _phantom: PhantomData<&'a mut ImageObject>
+}
+impl<'a> ImageMapping<'a> {
+    pub fn row_pitch(&self) -> usize { self.row_pitch }
+    pub fn slice_pitch(&self) -> usize { self.slice_pitch }
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.cast(), self.len) }
+    }
+    pub fn as_mut_bytes(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.cast(), self.len) }
+    }
+}
+impl<'a> Drop for ImageMapping<'a> {
+    fn drop(&mut self) { unsafe {
+        let mut done = null_mut();
+        let ret_code = clEnqueueUnmapMemObject(
+            self.device.ext.command_queue,
+            self.handle,
+            self.ptr,
+            0,
+            null(),
+            &mut done
+        );
+        if ret_code == CL_SUCCESS {
+            let _ = clWaitForEvents(1, &done);
+            let _ = clReleaseEvent(done);
+        }
+    } }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AddressingMode {
+    None, ClampToEdge, Clamp, Repeat, MirroredRepeat
+}
+#[derive(Debug, Clone, Copy)]
+pub enum FilterMode {
+    Nearest, Linear
+}
+pub struct Sampler {
+    handle: cl_sampler
+}
+unsafe impl Send for Sampler {}
+unsafe impl Sync for Sampler {}
+impl Sampler {
+    pub fn new(
+        normalized_coords: bool,
+        addressing: AddressingMode,
+        filter: FilterMode
+    ) -> Result<Sampler, ImageFailure> { unsafe {
+        let addressing = match addressing {
+            AddressingMode::None => CL_ADDRESS_NONE,
+            AddressingMode::ClampToEdge => CL_ADDRESS_CLAMP_TO_EDGE,
+            AddressingMode::Clamp => CL_ADDRESS_CLAMP,
+            AddressingMode::Repeat => CL_ADDRESS_REPEAT,
+            AddressingMode::MirroredRepeat => CL_ADDRESS_MIRRORED_REPEAT,
+        };
+        let filter = match filter {
+            FilterMode::Nearest => CL_FILTER_NEAREST,
+            FilterMode::Linear => CL_FILTER_LINEAR,
+        };
+        let props: [cl_sampler_properties;7] = [
+            CL_SAMPLER_NORMALIZED_COORDS as _, if normalized_coords { CL_TRUE } else { CL_FALSE } as _,
+            CL_SAMPLER_ADDRESSING_MODE as _, addressing as _,
+            CL_SAMPLER_FILTER_MODE as _, filter as _,
+            0
+        ];
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        let mut ret_code = CL_SUCCESS;
+        let handle = clCreateSamplerWithProperties(ctx, props.as_ptr(), &mut ret_code);
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                return Err(ImageFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_OPERATION => {
+                return Err(ImageFailure::Unsupported)
+            },
+            cl_sys::CL_INVALID_CONTEXT |
+            cl_sys::CL_INVALID_VALUE |
+            _ => unreachable!()
+        }
+        return Ok(Sampler { handle: handle });
+    } }
+}
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        let _ = unsafe { clReleaseSampler(self.handle) };
+    }
+}
+impl va_args_emu::KernelArgument for &Sampler {
+    fn as_opaque(&self) -> ErasedRef {
+        ErasedRef {
+            data_ptr: addr_of!(self.handle).cast(),
+            size: size_of::<cl_sampler>(),
+            alignment: align_of_val(&self.handle),
+            type_id: TypeId::of::<Sampler>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: None
+        }
+    }
+}
+
+impl Device {
+    fn supported_formats_of(&self, image_type: cl_mem_object_type) -> Result<Vec<ImageFormat>, OCLFailure> { unsafe {
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        let mut count = 0;
+        let ret_code = clGetSupportedImageFormats(
+            ctx,
+            CL_MEM_READ_WRITE,
+            image_type,
+            0,
+            null_mut(),
+            &mut count
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                return Err(OCLFailure::ResourcesExhausted)
+            },
+            _ => unreachable!()
+        }
+        // the driver rejects a buffer of no entries
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut raw = Vec::<cl_image_format>::new();
+        raw.reserve(count as _);
+        let ret_code = clGetSupportedImageFormats(
+            ctx,
+            CL_MEM_READ_WRITE,
+            image_type,
+            count,
+            raw.as_mut_ptr(),
+            null_mut()
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                return Err(OCLFailure::ResourcesExhausted)
+            },
+            _ => unreachable!()
+        }
+        raw.set_len(count as _);
+        let formats = raw.iter().filter_map(|fmt| {
+            let order = ChannelOrder::from_raw(fmt.image_channel_order)?;
+            let data_type = ChannelType::from_raw(fmt.image_channel_data_type)?;
+            Some(ImageFormat { order, data_type })
+        });
+        return Ok(formats.collect());
+    } }
+    pub fn supported_image_2d_formats(&self) -> Result<Vec<ImageFormat>, OCLFailure> {
+        self.supported_formats_of(CL_MEM_OBJECT_IMAGE2D)
+    }
+    pub fn supported_image_3d_formats(&self) -> Result<Vec<ImageFormat>, OCLFailure> {
+        self.supported_formats_of(CL_MEM_OBJECT_IMAGE3D)
+    }
+    pub fn supported_image_2d_array_formats(&self) -> Result<Vec<ImageFormat>, OCLFailure> {
+        self.supported_formats_of(CL_MEM_OBJECT_IMAGE2D_ARRAY)
+    }
+    fn create_image_object(
+        &self,
+        image_type: cl_mem_object_type,
+        format: ImageFormat,
+        extent: [usize;3]
+    ) -> Result<ImageObject, ImageFailure> { unsafe {
+        if extent.iter().any(|len| *len == 0) {
+            return Err(ImageFailure::InvalidSize);
+        }
+        let supported = match self.supported_formats_of(image_type) {
+            Ok(formats) => formats.contains(&format),
+            Err(_) => return Err(ImageFailure::ResourcesExhausted),
+        };
+        if !supported {
+            return Err(ImageFailure::UnsupportedFormat);
+        }
+        let raw_format = cl_image_format {
+            image_channel_order: format.order.raw(),
+            image_channel_data_type: format.data_type.raw()
+        };
+        let (depth, array_size) = match image_type {
+            CL_MEM_OBJECT_IMAGE2D => (0, 0),
+            CL_MEM_OBJECT_IMAGE3D => (extent[2], 0),
+            CL_MEM_OBJECT_IMAGE2D_ARRAY => (0, extent[2]),
+            _ => unreachable!()
+        };
+        let desc = cl_image_desc {
+            image_type: image_type,
+            image_width: extent[0],
+            image_height: extent[1],
+            image_depth: depth,
+            image_array_size: array_size,
+            image_row_pitch: 0,
+            image_slice_pitch: 0,
+            num_mip_levels: 0,
+            num_samples: 0,
+            buffer: null_mut()
+        };
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        let mut ret_code = CL_SUCCESS;
+        let handle = clCreateImage(
+            ctx,
+            CL_MEM_READ_WRITE,
+            &raw_format,
+            &desc,
+            null_mut(),
+            &mut ret_code
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY |
+            cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE => {
+                return Err(ImageFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_IMAGE_SIZE => {
+                return Err(ImageFailure::InvalidSize)
+            },
+            cl_sys::CL_IMAGE_FORMAT_NOT_SUPPORTED |
+            cl_sys::CL_INVALID_IMAGE_FORMAT_DESCRIPTOR => {
+                return Err(ImageFailure::UnsupportedFormat)
+            },
+            cl_sys::CL_INVALID_OPERATION => {
+                return Err(ImageFailure::Unsupported)
+            },
+            cl_sys::CL_INVALID_IMAGE_DESCRIPTOR |
+            cl_sys::CL_INVALID_VALUE |
+            cl_sys::CL_INVALID_CONTEXT |
+            _ => unreachable!()
+        }
+        let val = ImageObject {
+            handle: handle,
+            format: format,
+            extent: extent
+        };
+        return Ok(val);
+    } }
+    pub fn allocate_image_2d(
+        &self,
+        format: ImageFormat,
+        width: usize,
+        height: usize
+    ) -> Result<Image2d, ImageFailure> {
+        let obj = self.create_image_object(CL_MEM_OBJECT_IMAGE2D, format, [width, height, 1])?;
+        return Ok(Image2d(obj));
+    }
+    pub fn allocate_image_3d(
+        &self,
+        format: ImageFormat,
+        width: usize,
+        height: usize,
+        depth: usize
+    ) -> Result<Image3d, ImageFailure> {
+        let obj = self.create_image_object(CL_MEM_OBJECT_IMAGE3D, format, [width, height, depth])?;
+        return Ok(Image3d(obj));
+    }
+    pub fn allocate_image_2d_array(
+        &self,
+        format: ImageFormat,
+        width: usize,
+        height: usize,
+        array_len: usize
+    ) -> Result<Image2dArray, ImageFailure> {
+        let obj = self.create_image_object(CL_MEM_OBJECT_IMAGE2D_ARRAY, format, [width, height, array_len])?;
+        return Ok(Image2dArray(obj));
+    }
+    fn write_image_object<T: Copy>(&self, image: &ImageObject, data: &[T]) -> Result<(), ImageFailure> { unsafe {
+        if size_of_val(data) != image.byte_size() {
+            return Err(ImageFailure::SizeMismatch);
+        }
+        let origin: [usize;3] = [0;3];
+        let mut event = null_mut();
+        let ret_code = clEnqueueWriteImage(
+            self.ext.command_queue,
+            image.handle,
+            CL_TRUE,
+            origin.as_ptr(),
+            image.extent.as_ptr(),
+            0,
+            0,
+            data.as_ptr().cast(),
+            0,
+            null(),
+            trace::event_slot(&mut event)
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY |
+            cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE => {
+                return Err(ImageFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_IMAGE_SIZE |
+            cl_sys::CL_IMAGE_FORMAT_NOT_SUPPORTED => {
+                return Err(ImageFailure::UnsupportedFormat)
+            },
+            _ => unreachable!()
+        }
+        trace::record_copy(self.ext.command_queue, event, "write_image");
+        return Ok(());
+    } }
+    fn read_image_object<T: Copy>(&self, image: &ImageObject, data: &mut [T]) -> Result<(), ImageFailure> { unsafe {
+        if size_of_val(data) != image.byte_size() {
+            return Err(ImageFailure::SizeMismatch);
+        }
+        let origin: [usize;3] = [0;3];
+        let mut event = null_mut();
+        let ret_code = clEnqueueReadImage(
+            self.ext.command_queue,
+            image.handle,
+            CL_TRUE,
+            origin.as_ptr(),
+            image.extent.as_ptr(),
+            0,
+            0,
+            data.as_mut_ptr().cast(),
+            0,
+            null(),
+            trace::event_slot(&mut event)
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY |
+            cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE => {
+                return Err(ImageFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_IMAGE_SIZE |
+            cl_sys::CL_IMAGE_FORMAT_NOT_SUPPORTED => {
+                return Err(ImageFailure::UnsupportedFormat)
+            },
+            _ => unreachable!()
+        }
+        trace::record_copy(self.ext.command_queue, event, "read_image");
+        return Ok(());
+    } }
+    fn map_image_object<'a>(&'a self, image: &'a ImageObject) -> Result<ImageMapping<'a>, ImageFailure> { unsafe {
+        let origin: [usize;3] = [0;3];
+        let mut row_pitch = 0;
+        let mut slice_pitch = 0;
+        let mut ret_code = CL_SUCCESS;
+        let ptr = clEnqueueMapImage(
+            self.ext.command_queue,
+            image.handle,
+            CL_TRUE,
+            CL_MAP_READ | CL_MAP_WRITE,
+            origin.as_ptr(),
+            image.extent.as_ptr(),
+            &mut row_pitch,
+            &mut slice_pitch,
+            0,
+            null(),
+            null_mut(),
+            &mut ret_code
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY |
+            cl_sys::CL_MAP_FAILURE |
+            cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE => {
+                return Err(ImageFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_IMAGE_SIZE |
+            cl_sys::CL_IMAGE_FORMAT_NOT_SUPPORTED => {
+                return Err(ImageFailure::UnsupportedFormat)
+            },
+            _ => unreachable!()
+        }
+        let len = if slice_pitch != 0 {
+            slice_pitch * image.extent[2]
+        } else {
+            row_pitch * image.extent[1]
+        };
+        let val = ImageMapping {
+            device: self,
+            handle: image.handle,
+            ptr: ptr,
+            len: len,
+            row_pitch: row_pitch,
+            slice_pitch: slice_pitch,
+            _phantom: PhantomData
+        };
+        return Ok(val);
+    } }
+}
+
+#[test]
+fn sampled_image() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let format = ImageFormat { order: ChannelOrder::R, data_type: ChannelType::Float };
+    let image = dev.allocate_image_2d(format, 16, 16).unwrap();
+    let pixels = (0 .. 256).map(|i| i as f32).collect::<Vec<_>>();
+    image.write(dev, &pixels).unwrap();
+
+    let sampler = Sampler::new(false, AddressingMode::ClampToEdge, FilterMode::Nearest).unwrap();
+    let out = dev.allocate_buffer::<u32>(256).unwrap();
+
+    let text = r#"
+    __kernel void lol(read_only image2d_t image, sampler_t sampler, __global uint* out) {
+        int x = get_global_id(0);
+        int y = get_global_id(1);
+        float4 px = read_imagef(image, sampler, (int2)(x, y));
+        out[y * 16 + x] = (uint)px.x;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let kern = bundle.instantiate_kernel("lol", (&image, &sampler, out,)).unwrap();
+    let tok = dev.launch_kernel(kern, (16, 16), &[]).unwrap();
+    tok.await_completion().unwrap();
+
+    let mut ix = 0;
+    for i in out.as_items() {
+        assert!(*i == ix);
+        ix += 1;
+    }
+    dev.deallocate_memory(out);
+}
@@ -1,51 +1,131 @@
 #![feature(generic_arg_infer)]
 #![feature(fn_traits)]
 #![feature(unboxed_closures)]
+#![feature(allocator_api)]
 
 mod va_args_emu;
-
-
-use core::{alloc::Layout, any::TypeId, cell::UnsafeCell, marker::PhantomData, mem::{align_of, align_of_val, forget, size_of, size_of_val, transmute}, ptr::{addr_of, addr_of_mut, copy_nonoverlapping, drop_in_place, null, null_mut}, sync::atomic::{AtomicI32, AtomicU32, AtomicU8, Ordering}};
-
-use cl_sys::{self, c_void, clBuildProgram, clCreateCommandQueue, clCreateContext, clCreateKernel, clCreateProgramWithSource, clEnqueueNDRangeKernel, clGetCommandQueueInfo, clGetDeviceIDs, clGetDeviceInfo, clGetEventInfo, clGetKernelArgInfo, clGetKernelInfo, clGetPlatformInfo, clGetProgramInfo, clReleaseCommandQueue, clReleaseContext, clReleaseDevice, clReleaseEvent, clReleaseKernel, clReleaseProgram, clSVMFree, clSetEventCallback, clSetKernelArg, clSetKernelArgSVMPointer, clWaitForEvents, cl_bitfield, cl_command_queue, cl_command_queue_properties, cl_context, cl_device_id, cl_device_svm_capabilities, cl_event, cl_int, cl_kernel, cl_platform_id, cl_program, cl_uint, libc::c_ulong, size_t, CL_COMPLETE, CL_DEVICE_GLOBAL_MEM_SIZE, CL_DEVICE_MAX_COMPUTE_UNITS, CL_DEVICE_MAX_MEM_ALLOC_SIZE, CL_DEVICE_MAX_WORK_GROUP_SIZE, CL_DEVICE_PREFERRED_GLOBAL_ATOMIC_ALIGNMENT, CL_DEVICE_PREFERRED_PLATFORM_ATOMIC_ALIGNMENT, CL_DEVICE_SVM_ATOMICS, CL_DEVICE_SVM_CAPABILITIES, CL_DEVICE_SVM_FINE_GRAIN_BUFFER, CL_DEVICE_SVM_FINE_GRAIN_SYSTEM, CL_DEVICE_TYPE_ALL, CL_DEVICE_VERSION, CL_EVENT_COMMAND_EXECUTION_STATUS, CL_KERNEL_ARG_TYPE_NAME, CL_KERNEL_NUM_ARGS, CL_MEM_READ_WRITE, CL_MEM_SVM_ATOMICS, CL_MEM_SVM_FINE_GRAIN_BUFFER, CL_PLATFORM_VERSION, CL_PROGRAM_KERNEL_NAMES, CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE, CL_QUEUE_PROPERTIES, CL_SUCCESS};
-
-use va_args_emu::{KernelArguments, ErasedRef, SomePointer};
+mod svm_vec;
+mod svm_alloc;
+mod svm_atomics;
+mod nd_buffer;
+mod image;
+mod pipe;
+mod sub_buffer;
+mod file_io;
+mod chunked;
+mod token_async;
+mod token_wait;
+mod user_token;
+mod sync_points;
+mod profiling;
+mod trace;
+mod queue;
+mod device_queue;
+mod command_graph;
+mod task_graph;
+mod dep_tracking;
+mod scope;
+
+
+use core::{alloc::Layout, any::TypeId, marker::PhantomData, mem::{align_of, align_of_val, forget, offset_of, size_of, size_of_val, transmute}, ptr::{addr_of, addr_of_mut, copy_nonoverlapping, drop_in_place, null, null_mut}, sync::atomic::{AtomicBool, AtomicI32, AtomicPtr, AtomicU32, AtomicU8, Ordering}};
+use std::{collections::BTreeMap, os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd}, sync::{Arc, Mutex}};
+
+use cl_sys::{self, c_void, clBuildProgram, clCreateCommandQueueWithProperties, clCreateContext, clCreateKernel, clCreateProgramWithSource, clEnqueueNDRangeKernel, clGetCommandQueueInfo, clGetDeviceIDs, clGetDeviceInfo, clGetEventInfo, clGetKernelArgInfo, clGetKernelInfo, clGetPlatformInfo, clGetProgramInfo, clReleaseCommandQueue, clReleaseContext, clReleaseDevice, clReleaseEvent, clReleaseKernel, clReleaseProgram, clRetainCommandQueue, clRetainEvent, clSVMFree, clSetEventCallback, clSetKernelArg, clSetKernelArgSVMPointer, clSetKernelExecInfo, clWaitForEvents, cl_bool, cl_bitfield, cl_command_queue, cl_command_queue_properties, cl_queue_properties, cl_context, cl_device_id, cl_device_svm_capabilities, cl_event, cl_int, cl_kernel, cl_mem, cl_platform_id, cl_program, cl_uint, libc::c_ulong, size_t, CL_COMPLETE, CL_DEVICE_GLOBAL_MEM_SIZE, CL_DEVICE_MAX_COMPUTE_UNITS, CL_DEVICE_MAX_MEM_ALLOC_SIZE, CL_DEVICE_MAX_WORK_GROUP_SIZE, CL_DEVICE_MEM_BASE_ADDR_ALIGN, CL_DEVICE_PREFERRED_GLOBAL_ATOMIC_ALIGNMENT, CL_DEVICE_PREFERRED_PLATFORM_ATOMIC_ALIGNMENT, CL_DEVICE_SVM_ATOMICS, CL_DEVICE_SVM_CAPABILITIES, CL_DEVICE_SVM_FINE_GRAIN_BUFFER, CL_DEVICE_SVM_FINE_GRAIN_SYSTEM, CL_DEVICE_TYPE_ALL, CL_DEVICE_VERSION, CL_EVENT_COMMAND_EXECUTION_STATUS, CL_FALSE, CL_KERNEL_ARG_ACCESS_QUALIFIER, CL_KERNEL_ARG_ACCESS_READ_ONLY, CL_KERNEL_ARG_ACCESS_WRITE_ONLY, CL_KERNEL_ARG_TYPE_CONST, CL_KERNEL_ARG_TYPE_NAME, CL_KERNEL_ARG_TYPE_PIPE, CL_KERNEL_ARG_TYPE_QUALIFIER, CL_KERNEL_EXEC_INFO_SVM_FINE_GRAIN_SYSTEM, CL_KERNEL_EXEC_INFO_SVM_PTRS, CL_KERNEL_NUM_ARGS, CL_MEM_READ_WRITE, CL_MEM_SVM_ATOMICS, CL_MEM_SVM_FINE_GRAIN_BUFFER, CL_PIPE_PACKET_SIZE, CL_PLATFORM_VERSION, CL_PROGRAM_KERNEL_NAMES, CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE, CL_QUEUE_PROPERTIES, CL_SUCCESS, CL_TRUE};
+
+use va_args_emu::{KernelArguments, ErasedRef, SomePointer, SomeSvmPointer};
+use pipe::{packet_size_of, pipe_info, SomePipeReadEnd, SomePipeWriteEnd};
+use sub_buffer::SomeMemObject;
+use token_async::WakerSlot;
+use dep_tracking::AccessHistory;
+pub use svm_vec::{SvmVec, SvmVecFailure};
+pub use svm_alloc::SvmAllocator;
+pub use svm_atomics::SvmAtomicItem;
+pub use nd_buffer::{NdBuffer, NdBufferFailure, NdView, NdDims};
+pub use image::{Image2d, Image3d, Image2dArray, ImageFormat, ChannelOrder, ChannelType, ImageMapping, ImageFailure, Sampler, AddressingMode, FilterMode};
+pub use pipe::{Pipe, PipeReadEnd, PipeWriteEnd, PipeFailure};
+pub use sub_buffer::{SvmSlice, Buffer, SubBufferFailure};
+pub use file_io::{FileTransferFailure, PlainData};
+pub use chunked::{ChunkedBuffer, ChunkedLaunchFailure};
+pub use user_token::{UserToken, UserTokenFailure};
+pub use sync_points::SyncPointFailure;
+pub use profiling::{LaunchProfile, ProfilingFailure};
+#[cfg(feature = "opencl_2_1")]
+pub use profiling::TimerSync;
+pub use trace::TraceRecorder;
+pub use queue::{Queue, QueueOptions, QueuePriority, QueueThrottle};
+pub use device_queue::{DeviceQueue, DeviceQueueProps, DeviceQueueFailure};
+pub use command_graph::{CommandGraph, NodeId, GraphFailure};
+pub use task_graph::{TaskGraph, TaskId, TaskAccess, TaskGraphFailure};
+pub use scope::{Scope, ScopedKernel, HostSlice, ScopeFailure};
 
 #[derive(Debug, Clone, Copy)]
 pub enum OCLFailure {
     ResourcesExhausted, NoPlatforms, InvalidProgramm
 }
-#[derive(Debug, Clone, Copy)] #[repr(C)]
+#[derive(Debug)] #[repr(C)]
 pub struct MemoryRef<T> {
     ptr: *mut c_void,
     count: usize,
+    generation: u32,
+    atomics: bool,
     _phantom: PhantomData<T>
 }
+impl<T> Clone for MemoryRef<T> {
+    fn clone(&self) -> Self { *self }
+}
+impl<T> Copy for MemoryRef<T> {}
 impl<T> MemoryRef<T> {
     pub fn len(&self) -> usize { self.count }
+    pub fn supports_atomics(&self) -> bool { self.atomics }
     pub fn as_mut_ptr(&self) -> *mut T {
         self.ptr.cast()
     }
     pub fn as_single_item(&self) -> &T {
         assert!(self.count == 1);
+        self.await_host_access(false);
         unsafe {&*self.ptr.cast()}
     }
     pub fn as_single_mut_item(&mut self) -> &mut T {
         assert!(self.count == 1);
+        self.await_host_access(true);
         unsafe {&mut *self.ptr.cast()}
     }
     pub fn as_items(&self) -> &[T] {
+        self.await_host_access(false);
         unsafe { core::slice::from_raw_parts(self.ptr.cast(), self.count) }
     }
     pub fn as_mut_items(&mut self) -> &mut [T] {
+        self.await_host_access(true);
         unsafe { core::slice::from_raw_parts_mut(self.ptr.cast(), self.count) }
     }
+    // Copies share the tracker of their allocation, a freed one has nothing to track
+    pub fn set_dependency_tracking(&self, enabled: bool) {
+        if let Some(tracker) = allocation_tracker(self.ptr) {
+            tracker.set_tracking(enabled);
+        }
+    }
+    fn await_host_access(&self, exclusive: bool) {
+        if !dep_tracking::any_tracking() {
+            return;
+        }
+        if let Some(tracker) = allocation_tracker(self.ptr) {
+            tracker.await_host_access(exclusive);
+        }
+    }
 }
 #[repr(C)]
 struct SomeMemoryRef {
     ptr: *mut c_void,
     _count: usize,
 }
+// binding reads the pointer of all of these through SomeMemoryRef
+const _: () = {
+    assert!(offset_of!(SomeMemoryRef, ptr) == 0);
+    assert!(offset_of!(MemoryRef<u8>, ptr) == 0);
+    assert!(offset_of!(SvmVec<u8>, ptr) == 0);
+    assert!(offset_of!(NdBuffer<u8, 1>, mem) == 0);
+    assert!(offset_of!(SvmSlice<u8>, ptr) == 0);
+};
 impl<T> va_args_emu::KernelArgument for MemoryRef<T> {
     fn as_opaque(&self) -> ErasedRef {
         ErasedRef {
@@ -53,9 +133,225 @@ impl<T> va_args_emu::KernelArgument for MemoryRef<T> {
             size: size_of_val(self),
             alignment: align_of_val(self),
             type_id: TypeId::of::<SomeMemoryRef>(),
-            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())}
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: allocation_tracker(self.ptr)
+        }
+    }
+}
+// Shared between a buffer and every kernel it is bound to.
+// Launches hold a lease until their token completes. The epoch
+// moves whenever the memory is freed or moved, after which kernels
+// bound before that refuse to launch.
+#[doc(hidden)]
+pub struct BufferTracker {
+    pending_launches: AtomicU32,
+    epoch: AtomicU32,
+    tracking: AtomicBool,
+    history: Mutex<AccessHistory>,
+    // host memory lent by a scope, only `Scope::launch` may use it
+    lent: bool
+}
+impl BufferTracker {
+    fn new() -> Self {
+        BufferTracker::with_lent(false)
+    }
+    fn new_lent() -> Self {
+        BufferTracker::with_lent(true)
+    }
+    fn with_lent(lent: bool) -> Self {
+        BufferTracker {
+            pending_launches: AtomicU32::new(0),
+            epoch: AtomicU32::new(0),
+            tracking: AtomicBool::new(false),
+            history: Mutex::new(AccessHistory::default()),
+            lent: lent
+        }
+    }
+    fn acquire(&self) {
+        self.pending_launches.fetch_add(1, Ordering::SeqCst);
+    }
+    fn release(&self) { unsafe {
+        let prior = self.pending_launches.fetch_sub(1, Ordering::Release);
+        if prior == 1 {
+            libc::syscall(
+                libc::SYS_futex,
+                &self.pending_launches,
+                libc::FUTEX_WAKE,
+                u32::MAX,
+                0,
+                0
+            );
+        }
+    } }
+    fn has_pending_launches(&self) -> bool {
+        self.pending_launches.load(Ordering::SeqCst) != 0
+    }
+    fn await_pending_launches(&self) { unsafe {
+        loop {
+            let pending = self.pending_launches.load(Ordering::SeqCst);
+            if pending == 0 { break }
+            let _ = libc::syscall(
+                libc::SYS_futex,
+                &self.pending_launches,
+                libc::FUTEX_WAIT,
+                pending,
+                0,
+                0
+            );
+        }
+    } }
+    fn epoch(&self) -> u32 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+    // Lease and epoch bump are both SeqCst, so either the launch
+    // sees the new epoch or the retiring side sees the lease
+    fn try_lease(&self, epoch: u32) -> bool {
+        let prior = self.pending_launches.fetch_add(1, Ordering::SeqCst);
+        if prior & RETIRING != 0 || self.epoch() != epoch {
+            self.release();
+            return false;
+        }
+        return true;
+    }
+    fn retire(&self) {
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.await_pending_launches();
+    }
+    // Claims the lease count first, so a refused retire leaves
+    // the epoch and with it every binding intact
+    fn try_retire(&self) -> bool {
+        let claimed = self.pending_launches.compare_exchange(0, RETIRING, Ordering::SeqCst, Ordering::SeqCst);
+        if claimed.is_err() {
+            return false;
+        }
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.pending_launches.fetch_sub(RETIRING, Ordering::SeqCst);
+        return true;
+    }
+}
+// Set in the lease count while a retire is underway, leases taken meanwhile are refused
+const RETIRING: u32 = 1 << 31;
+// Trackers of allocations that have no owner to keep one,
+// by base address and size
+static ALLOCATIONS: Mutex<BTreeMap<usize, (usize, Arc<BufferTracker>)>> = Mutex::new(BTreeMap::new());
+pub(crate) fn register_allocation(ptr: *mut c_void, size: usize) {
+    let tracker = Arc::new(BufferTracker::new());
+    ALLOCATIONS.lock().unwrap().insert(ptr as usize, (size, tracker));
+}
+// Tracker of the allocation holding `ptr`
+pub(crate) fn allocation_tracker(ptr: *const c_void) -> Option<Arc<BufferTracker>> {
+    let addr = ptr as usize;
+    let allocations = ALLOCATIONS.lock().unwrap();
+    let (base, (size, tracker)) = allocations.range(..= addr).next_back()?;
+    if addr - base >= *size {
+        return None;
+    }
+    return Some(tracker.clone());
+}
+// Waits for launches holding the allocation before it can be freed,
+// false if `ptr` is not the base of a live allocation
+pub(crate) fn retire_allocation(ptr: *mut c_void) -> bool {
+    let entry = ALLOCATIONS.lock().unwrap().remove(&(ptr as usize));
+    match entry {
+        Some((_, tracker)) => {
+            tracker.retire();
+            return true;
+        },
+        None => return false,
+    }
+}
+pub(crate) fn is_allocation_base(ptr: *mut c_void) -> bool {
+    ALLOCATIONS.lock().unwrap().contains_key(&(ptr as usize))
+}
+// A buffer as it was when bound to a kernel
+#[derive(Clone)]
+pub(crate) struct Binding {
+    tracker: Arc<BufferTracker>,
+    epoch: u32,
+    writes: bool
+}
+impl Binding {
+    pub(crate) fn new(tracker: Arc<BufferTracker>, writes: bool) -> Binding {
+        let epoch = tracker.epoch();
+        Binding {
+            tracker: tracker,
+            epoch: epoch,
+            writes: writes
         }
     }
+    pub(crate) fn is_stale(&self) -> bool {
+        self.tracker.epoch() != self.epoch
+    }
+}
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum EnqueueFailure {
+    Released,
+    ResourcesExhausted,
+    Rejected(cl_int)
+}
+fn release_all(trackers: &[Arc<BufferTracker>]) {
+    for tracker in trackers {
+        tracker.release();
+    }
+}
+// Leases every binding for the command that `enqueue` puts on a queue.
+// The command waits behind a gate until its completion callback owns
+// the leases, so it never runs without them.
+pub(crate) fn enqueue_leased<'a>(
+    bindings: impl Iterator<Item = &'a Binding> + Clone,
+    dependencies: &[&Token],
+    enqueue: impl FnOnce(&[cl_event], &mut cl_event) -> cl_int
+) -> Result<Token, EnqueueFailure> {
+    // held until the command is recorded, so commands from other
+    // threads see either none or all of its accesses
+    let mut hazards = dep_tracking::lock_accesses(bindings.clone().map(|b| (&b.tracker, b.writes)));
+    let mut leased = Vec::new();
+    for binding in bindings {
+        if !binding.tracker.try_lease(binding.epoch) {
+            release_all(&leased);
+            return Err(EnqueueFailure::Released);
+        }
+        leased.push(binding.tracker.clone());
+    }
+    let gate = if leased.is_empty() {
+        None
+    } else {
+        match UserToken::new() {
+            Ok(gate) => Some(gate),
+            Err(_) => {
+                release_all(&leased);
+                return Err(EnqueueFailure::ResourcesExhausted);
+            },
+        }
+    };
+    let mut deps = dependencies.iter().map(|t| t.0.token).collect::<Vec<_>>();
+    deps.extend(hazards.wait_list());
+    if let Some(gate) = &gate {
+        deps.push(gate.0.token);
+    }
+    let mut event = null_mut();
+    let ret_code = enqueue(&deps, &mut event);
+    if ret_code != CL_SUCCESS {
+        release_all(&leased);
+        return Err(EnqueueFailure::Rejected(ret_code));
+    }
+    let tok = Token::from_event(event);
+    if let Some(gate) = gate {
+        let held = leased.clone();
+        let attached = tok.attach_completion_callback(move |_| release_all(&held));
+        if attached.is_err() {
+            // the dropped gate fails the command before it starts
+            drop(gate);
+            release_all(&leased);
+            return Err(EnqueueFailure::ResourcesExhausted);
+        }
+        if gate.complete().is_err() {
+            // same, except the callback gives the leases back
+            return Err(EnqueueFailure::ResourcesExhausted);
+        }
+    }
+    hazards.record(&tok);
+    return Ok(tok);
 }
 #[derive(Debug, Clone, Copy)]
 pub enum KernelCreationFailure {
@@ -63,21 +359,123 @@ pub enum KernelCreationFailure {
     InvalidArgument(u32),
     InvalidKernelName,
     ArgNumMismatch(u32, u32),
-    ArgTypeMismatch(u32)
+    ArgTypeMismatch(u32),
+    ArgAccessMismatch(u32),
+    InvalidDeviceQueue(u32)
 }
+// Argument setters are not thread safe per kernel, hence `&mut self`
 pub struct Kernel {
-    handle: cl_kernel
+    handle: cl_kernel,
+    bindings: Vec<Binding>,
+    indirect_bindings: Vec<Binding>,
+    device_queues: Vec<cl_command_queue>
 }
-impl Drop for Kernel {
-    fn drop(&mut self) {
-        let _ = unsafe { clReleaseKernel(self.handle) };
+unsafe impl Send for Kernel {}
+unsafe impl Sync for Kernel {}
+#[derive(Debug, Clone, Copy)]
+pub enum ExecInfoFailure {
+    ResourcesExhausted,
+    ForeignAllocation(usize),
+    Unsupported
+}
+pub trait SvmRegion {
+    fn svm_base_ptr(&self) -> *mut c_void;
+    fn context_generation(&self) -> u32;
+    #[doc(hidden)]
+    fn tracker(&self) -> Option<Arc<BufferTracker>> { None }
+}
+impl<T> SvmRegion for MemoryRef<T> {
+    fn svm_base_ptr(&self) -> *mut c_void { self.ptr }
+    fn context_generation(&self) -> u32 { self.generation }
+    fn tracker(&self) -> Option<Arc<BufferTracker>> { allocation_tracker(self.ptr) }
+}
+impl Kernel {
+    pub(crate) fn lent_trackers(&self) -> impl Iterator<Item = &Arc<BufferTracker>> {
+        self.bindings.iter().map(|b| &b.tracker).filter(|t| t.lent)
     }
+    pub fn declare_indirect_access(
+        &mut self,
+        regions: &[&dyn SvmRegion]
+    ) -> Result<(), ExecInfoFailure> { unsafe {
+        // The driver rejects an empty list
+        if regions.is_empty() {
+            self.indirect_bindings.clear();
+            return Ok(());
+        }
+        let current = OCL_SHARED_CONTEXT.generation;
+        let mut ptrs = Vec::new();
+        ptrs.reserve(regions.len());
+        for (ix, region) in regions.iter().enumerate() {
+            let ptr = region.svm_base_ptr();
+            if region.context_generation() != current || ptr.is_null() {
+                return Err(ExecInfoFailure::ForeignAllocation(ix));
+            }
+            ptrs.push(ptr);
+        }
+        let ret_code = clSetKernelExecInfo(
+            self.handle,
+            CL_KERNEL_EXEC_INFO_SVM_PTRS,
+            size_of::<*mut c_void>() * ptrs.len(),
+            ptrs.as_ptr().cast()
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                return Err(ExecInfoFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_OPERATION => {
+                return Err(ExecInfoFailure::Unsupported)
+            },
+            cl_sys::CL_INVALID_KERNEL |
+            cl_sys::CL_INVALID_VALUE |
+            _ => unreachable!()
+        }
+        self.indirect_bindings = regions.iter()
+            .filter_map(|region| region.tracker())
+            .map(|tracker| Binding::new(tracker, true))
+            .collect();
+        return Ok(());
+    } }
+    pub fn allow_system_svm_access(&mut self, allow: bool) -> Result<(), ExecInfoFailure> { unsafe {
+        let value: cl_bool = if allow { CL_TRUE } else { CL_FALSE };
+        let ret_code = clSetKernelExecInfo(
+            self.handle,
+            CL_KERNEL_EXEC_INFO_SVM_FINE_GRAIN_SYSTEM,
+            size_of::<cl_bool>(),
+            addr_of!(value).cast()
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                return Err(ExecInfoFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_OPERATION => {
+                return Err(ExecInfoFailure::Unsupported)
+            },
+            cl_sys::CL_INVALID_KERNEL |
+            cl_sys::CL_INVALID_VALUE |
+            _ => unreachable!()
+        }
+        return Ok(());
+    } }
+}
+impl Drop for Kernel {
+    fn drop(&mut self) { unsafe {
+        for queue in &self.device_queues {
+            let _ = clReleaseCommandQueue(*queue);
+        }
+        let _ = clReleaseKernel(self.handle);
+    } }
 }
 
 pub struct CodeBundle {
     handle: cl_program,
     kern_names: Vec<u8>
 }
+unsafe impl Send for CodeBundle {}
+unsafe impl Sync for CodeBundle {}
 impl CodeBundle {
     pub fn get_available_kernel_names(&self) -> impl Iterator<Item =  &str> {
         let mut last_pivot = 0;
@@ -131,6 +529,7 @@ impl CodeBundle {
         let comp_args = "-cl-no-signed-zeros -cl-std=CL2.0 -cl-kernel-arg-info -O2\0";
         let devs = OCL_SHARED_CONTEXT.dev_ids.as_ptr();
         let devs_len = OCL_SHARED_CONTEXT.len;
+        let build_started = std::time::Instant::now();
         let ret_code = clBuildProgram(
             cl_prog,
             devs_len,
@@ -186,6 +585,7 @@ impl CodeBundle {
             }
         }
         kern_name_bytes.set_len(len);
+        trace::record_build(build_started, &kern_name_bytes);
 
         let val = CodeBundle {
             handle: cl_prog,
@@ -219,9 +619,24 @@ impl CodeBundle {
             cl_sys::CL_INVALID_PROGRAM_EXECUTABLE |
             _ => unreachable!()
         }
+        let mut kernel = Kernel {
+            handle: kern_ptr,
+            bindings: Vec::new(),
+            indirect_bindings: Vec::new(),
+            device_queues: Vec::new()
+        };
+        kernel.rebind(args)?;
+        return Ok(kernel)
+    } }
+}
+impl Kernel {
+    pub fn rebind(
+        &mut self,
+        args: impl KernelArguments
+    ) -> Result<(), KernelCreationFailure> { unsafe {
         let mut arg_count = 0u32;
         let ret_code = clGetKernelInfo(
-            kern_ptr,
+            self.handle,
             CL_KERNEL_NUM_ARGS,
             size_of::<cl_uint>(),
             addr_of_mut!(arg_count).cast(),
@@ -242,16 +657,21 @@ impl CodeBundle {
         if actual != expected {
             return Err(KernelCreationFailure::ArgNumMismatch(expected, actual));
         }
+        // Every argument is checked before any is set, the iterator keeps
+        // the argument values alive until then
         let mut iter = args.iter();
         let mut ix = 0;
-        let mut arg_ty_nm = [0u8;16];
+        let mut arg_ty_nm = [0u8;64];
         let mut arg_ty_nm_len = 0;
-        while let Some(ErasedRef { data_ptr:ptr, size, alignment:_, type_id:id, dctor:_  }) = iter.next() {
+        let mut checked = Vec::new();
+        checked.reserve(actual as usize);
+        while ix < actual {
+            let Some(ErasedRef { data_ptr:ptr, size, alignment:_, type_id:id, dctor:_, tracker }) = iter.next() else { unreachable!() };
             let ret_code = clGetKernelArgInfo(
-                kern_ptr,
+                self.handle,
                 ix,
                 CL_KERNEL_ARG_TYPE_NAME,
-                16,
+                64,
                 arg_ty_nm.as_mut_ptr().cast(),
                 &mut arg_ty_nm_len
             );
@@ -264,12 +684,69 @@ impl CodeBundle {
             }
             let slice = core::slice::from_raw_parts(arg_ty_nm.as_ptr(), arg_ty_nm_len);
             let str = core::str::from_utf8_unchecked(slice);
+            let mut arg_ty_qual: cl_bitfield = 0;
+            let ret_code = clGetKernelArgInfo(
+                self.handle,
+                ix,
+                CL_KERNEL_ARG_TYPE_QUALIFIER,
+                size_of::<cl_bitfield>(),
+                addr_of_mut!(arg_ty_qual).cast(),
+                null_mut()
+            );
+            match ret_code {
+                cl_sys::CL_SUCCESS => (),
+                _ => unreachable!()
+            }
+            let is_pipe = arg_ty_qual & CL_KERNEL_ARG_TYPE_PIPE != 0;
             let is_pointer = str.contains('*');
-            if is_pointer {
-                let okay = id == TypeId::of::<SomePointer>() || id == TypeId::of::<SomeMemoryRef>();
+            if is_pipe {
+                let mut access: cl_uint = 0;
+                let ret_code = clGetKernelArgInfo(
+                    self.handle,
+                    ix,
+                    CL_KERNEL_ARG_ACCESS_QUALIFIER,
+                    size_of::<cl_uint>(),
+                    addr_of_mut!(access).cast(),
+                    null_mut()
+                );
+                match ret_code {
+                    cl_sys::CL_SUCCESS => (),
+                    _ => unreachable!()
+                }
+                let is_pipe_end =
+                    id == TypeId::of::<SomePipeReadEnd>() ||
+                    id == TypeId::of::<SomePipeWriteEnd>();
+                if !is_pipe_end {
+                    return Err(KernelCreationFailure::ArgTypeMismatch(ix));
+                }
+                let expected_end = match access {
+                    CL_KERNEL_ARG_ACCESS_READ_ONLY => TypeId::of::<SomePipeReadEnd>(),
+                    CL_KERNEL_ARG_ACCESS_WRITE_ONLY => TypeId::of::<SomePipeWriteEnd>(),
+                    _ => unreachable!()
+                };
+                if id != expected_end {
+                    return Err(KernelCreationFailure::ArgAccessMismatch(ix));
+                }
+                if let Some(expected_size) = packet_size_of(str) {
+                    let handle = *ptr.cast::<cl_mem>();
+                    match pipe_info(handle, CL_PIPE_PACKET_SIZE) {
+                        Ok(size) if size as usize == expected_size => (),
+                        Ok(_) => return Err(KernelCreationFailure::ArgTypeMismatch(ix)),
+                        Err(_) => return Err(KernelCreationFailure::ResourcesExhausted),
+                    }
+                }
+            } else if is_pointer {
+                let okay =
+                    id == TypeId::of::<SomePointer>() ||
+                    id == TypeId::of::<SomeMemoryRef>() ||
+                    id == TypeId::of::<SomeSvmPointer>() ||
+                    id == TypeId::of::<SomeMemObject>();
                 if !okay {
                     return Err(KernelCreationFailure::ArgTypeMismatch(ix));
                 }
+                if id == TypeId::of::<SomeSvmPointer>() && ptr.is_null() {
+                    return Err(KernelCreationFailure::InvalidArgument(ix));
+                }
             } else {
                 match str {
                     "char\0" => {
@@ -312,45 +789,107 @@ impl CodeBundle {
                             return Err(KernelCreationFailure::ArgTypeMismatch(ix));
                         }
                     },
+                    "ulong4\0" => {
+                        if id != TypeId::of::<NdDims>() {
+                            return Err(KernelCreationFailure::ArgTypeMismatch(ix));
+                        }
+                    },
+                    "image2d_t\0" => {
+                        if id != TypeId::of::<Image2d>() {
+                            return Err(KernelCreationFailure::ArgTypeMismatch(ix));
+                        }
+                    },
+                    "image3d_t\0" => {
+                        if id != TypeId::of::<Image3d>() {
+                            return Err(KernelCreationFailure::ArgTypeMismatch(ix));
+                        }
+                    },
+                    "image2d_array_t\0" => {
+                        if id != TypeId::of::<Image2dArray>() {
+                            return Err(KernelCreationFailure::ArgTypeMismatch(ix));
+                        }
+                    },
+                    "sampler_t\0" => {
+                        if id != TypeId::of::<Sampler>() {
+                            return Err(KernelCreationFailure::ArgTypeMismatch(ix));
+                        }
+                    },
+                    "queue_t\0" => {
+                        if id != TypeId::of::<DeviceQueue>() {
+                            return Err(KernelCreationFailure::ArgTypeMismatch(ix));
+                        }
+                    },
                     _ => unreachable!()
                 }
             }
+            let writes = arg_ty_qual & CL_KERNEL_ARG_TYPE_CONST == 0;
+            checked.push((ptr, size, id, tracker, writes));
+            ix += 1;
+        }
+        let mut bindings = Vec::new();
+        let mut device_queues = Vec::new();
+        let mut failure = None;
+        for (ix, (ptr, size, id, tracker, writes)) in checked.into_iter().enumerate() {
+            let ix = ix as u32;
             let ret_c ;
             match id {
                 _ if id == TypeId::of::<SomeMemoryRef>() => {
                     let ptr = (*ptr.cast::<SomeMemoryRef>()).ptr;
-                    ret_c = clSetKernelArgSVMPointer(kern_ptr, ix, ptr);
+                    ret_c = clSetKernelArgSVMPointer(self.handle, ix, ptr);
+                },
+                _ if id == TypeId::of::<SomeSvmPointer>() => {
+                    ret_c = clSetKernelArgSVMPointer(self.handle, ix, ptr.cast());
                 },
                 _ => {
-                    ret_c = clSetKernelArg(kern_ptr, ix, size, ptr.cast());
+                    ret_c = clSetKernelArg(self.handle, ix, size, ptr.cast());
                 }
             }
             match ret_c {
                 cl_sys::CL_SUCCESS => (),
                 cl_sys::CL_OUT_OF_RESOURCES |
                 cl_sys::CL_OUT_OF_HOST_MEMORY => {
-                    return Err(KernelCreationFailure::ResourcesExhausted)
+                    failure = Some(KernelCreationFailure::ResourcesExhausted);
                 },
                 cl_sys::CL_INVALID_DEVICE_QUEUE => {
-                    panic!("Invalid device queue!")
+                    failure = Some(KernelCreationFailure::InvalidDeviceQueue(ix));
                 },
                 cl_sys::CL_INVALID_ARG_INDEX |
                 cl_sys::CL_INVALID_ARG_VALUE |
                 cl_sys::CL_INVALID_ARG_SIZE |
                 cl_sys::CL_INVALID_MEM_OBJECT |
                 cl_sys::CL_INVALID_SAMPLER => {
-                    return Err(KernelCreationFailure::InvalidArgument(ix));
+                    failure = Some(KernelCreationFailure::InvalidArgument(ix));
                 }
                 cl_sys::CL_INVALID_KERNEL |
                 _ => unreachable!()
             }
-            ix += 1;
+            if failure.is_some() {
+                break;
+            }
+            if let Some(tracker) = tracker {
+                bindings.push(Binding::new(tracker, writes));
+            }
+            if id == TypeId::of::<DeviceQueue>() {
+                device_queues.push(*ptr.cast::<cl_command_queue>());
+            }
         }
-
-        let res = Kernel {
-            handle: kern_ptr
-        };
-        return Ok(res)
+        // The kernel carries no borrow of the queues it was bound to
+        for queue in &device_queues {
+            let _ = clRetainCommandQueue(*queue);
+        }
+        // The driver refused an argument after some were already set. The
+        // kernel keeps holding both the old and the new ones, as either
+        // may still be in place.
+        if let Some(failure) = failure {
+            self.bindings.extend(bindings);
+            self.device_queues.extend(device_queues);
+            return Err(failure);
+        }
+        for queue in core::mem::replace(&mut self.device_queues, device_queues) {
+            let _ = clReleaseCommandQueue(queue);
+        }
+        self.bindings = bindings;
+        return Ok(())
     } }
 }
 impl Drop for CodeBundle {
@@ -361,23 +900,69 @@ impl Drop for CodeBundle {
 
 #[derive(Debug, Clone, Copy)]
 pub enum CompletionAwaitFailure {
-    NoMem, JobFinishedWithError
+    NoMem, JobFinishedWithError, TimedOut, NoTokens
 }
 #[derive(Debug, Clone, Copy)]
 pub enum ExecutionState {
     Queued, Submited, Running, Complete
 }
+// The states the driver can call back on, it has none for queued
+#[derive(Debug, Clone, Copy)]
+pub enum CallbackState {
+    Submited, Running, Complete
+}
+#[derive(Debug, Clone, Copy)]
+pub enum ExecutionError {
+    ResourcesExhausted,
+    AllocationFailure,
+    DependencyFailed,
+    Other(i32)
+}
+impl ExecutionError {
+    fn decode(status: cl_int) -> Result<ExecutionState, ExecutionError> {
+        let val = match status {
+            cl_sys::CL_COMPLETE => ExecutionState::Complete,
+            cl_sys::CL_RUNNING => ExecutionState::Running,
+            cl_sys::CL_SUBMITTED => ExecutionState::Submited,
+            cl_sys::CL_QUEUED => ExecutionState::Queued,
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => return Err(ExecutionError::ResourcesExhausted),
+            cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE => return Err(ExecutionError::AllocationFailure),
+            cl_sys::CL_EXEC_STATUS_ERROR_FOR_EVENTS_IN_WAIT_LIST => return Err(ExecutionError::DependencyFailed),
+            _ => return Err(ExecutionError::Other(status)),
+        };
+        return Ok(val);
+    }
+}
 
 
+// Lazily created parts sit behind locks, since any of them
+// can be requested through a shared token from several threads
 struct TokenInner {
     token: cl_event,
-    file_desc: i32,
-    futex: i32
+    file_desc: Mutex<Option<Arc<EventFd>>>,
+    futex: Arc<AtomicI32>,
+    waker_slot: Mutex<Option<Arc<WakerSlot>>>
 }
-pub struct Token(UnsafeCell<TokenInner>);
+pub struct Token(TokenInner);
+// Event handles are thread safe per the OpenCL spec
+unsafe impl Send for Token {}
+unsafe impl Sync for Token {}
 impl Token {
+    fn from_event(event: cl_event) -> Token {
+        Token(TokenInner {
+            token: event,
+            file_desc: Mutex::new(None),
+            futex: Arc::new(AtomicI32::new(1)),
+            waker_slot: Mutex::new(None)
+        })
+    }
+    pub(crate) fn retained(&self) -> Token {
+        let _ = unsafe { clRetainEvent(self.0.token) };
+        Token::from_event(self.0.token)
+    }
     pub fn await_completion(&self) -> Result<(), CompletionAwaitFailure> { unsafe {
-        let this = &mut *self.0.get();
+        let this = &self.0;
         match clWaitForEvents(1, &this.token) {
             cl_sys::CL_SUCCESS => (),
             cl_sys::CL_OUT_OF_RESOURCES |
@@ -395,7 +980,7 @@ impl Token {
         return Ok(());
     } }
     pub fn get_execution_state(&self) -> Result<ExecutionState, OCLFailure> { unsafe {
-        let this = &mut *self.0.get();
+        let this = &self.0;
         let mut value = 0;
         let ret_code = clGetEventInfo(
             this.token,
@@ -422,8 +1007,37 @@ impl Token {
 
         return Ok(val);
     } }
-    pub fn attach_completion_callback<F: FnMut(cl_int) -> ()>(
+    pub fn attach_completion_callback<F>(
+        &self,
+        action: F
+    ) -> Result<(), OCLFailure>
+        where F: FnOnce(Result<ExecutionState, ExecutionError>) + Send + 'static
+    {
+        self.attach_callback(CallbackState::Complete, action)
+    }
+    // The action runs on a driver thread and must not block on other tokens
+    pub fn attach_callback<F>(
         &self,
+        state: CallbackState,
+        action: F
+    ) -> Result<(), OCLFailure>
+        where F: FnOnce(Result<ExecutionState, ExecutionError>) + Send + 'static
+    {
+        let exec_type = match state {
+            CallbackState::Submited => cl_sys::CL_SUBMITTED,
+            CallbackState::Running => cl_sys::CL_RUNNING,
+            CallbackState::Complete => cl_sys::CL_COMPLETE,
+        };
+        let mut action = Some(action);
+        self.attach_raw_callback(exec_type, move |code| {
+            if let Some(action) = action.take() {
+                action(ExecutionError::decode(code))
+            }
+        })
+    }
+    fn attach_raw_callback<F: FnMut(cl_int) -> ()>(
+        &self,
+        exec_type: cl_int,
         action: F
     ) -> Result<(), OCLFailure> { unsafe {
         #[repr(C)]
@@ -474,10 +1088,10 @@ impl Token {
         type ClCallback = extern "C" fn(cl_event, cl_int, *mut c_void) ;
         let fptr = transmute::<_, ClCallback>(invoker as *mut ());
 
-        let this = &mut *self.0.get();
+        let this = &self.0;
         let ret_code = clSetEventCallback(
             this.token,
-            CL_COMPLETE,
+            exec_type,
             Some(fptr),
             user_data_ptr
         );
@@ -485,93 +1099,87 @@ impl Token {
             cl_sys::CL_SUCCESS => (),
             cl_sys::CL_OUT_OF_RESOURCES |
             cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                dctor(pivoted_mem.cast());
+                std::alloc::dealloc(mem_origin, Layout::from_size_align_unchecked(closure_size, 8));
                 return Err(OCLFailure::ResourcesExhausted)
             },
             _ => unreachable!()
         }
         return Ok(());
     } }
-    // pub fn as_fd(&self) -> Result<i32, OCLFailure> { unsafe {
-    //     let this = &mut *self.0.get();
-    //     if this.file_desc != -1 {
-    //         return Ok(this.file_desc);
-    //     }
-    //     let fd_nm = "__OCL_WAIT_TOKEN__\0";
-    //     let fd = libc::memfd_create(fd_nm.as_ptr().cast(), 0);
-    //     if fd == -1 {
-    //         match *libc::__errno_location() {
-    //             libc::ENOMEM |
-    //             libc::ENFILE |
-    //             libc::EMFILE => {
-    //                 return Err(OCLFailure::ResourcesExhausted);
-    //             },
-    //             libc::EFAULT |
-    //             libc::EINVAL |
-    //             libc::EPERM |
-    //             _ => unreachable!()
-    //         }
-    //     }
-    //     this.file_desc = fd;
-    //     unsafe extern "C" fn invoker(_:cl_event, _:cl_int, ud: *mut ()) {
-    //         let fd = ud as i32;
-    //         let str = "done";
-    //         loop {
-    //             let outcome = libc::write(fd, str.as_ptr().cast(), str.len());
-    //             if outcome == -1 {
-    //                 match *libc::__errno_location() {
-    //                     libc:: ENOSPC |
-    //                     libc::EDQUOT => {
-    //                         panic!("Resources exhausted")
-    //                     }
-    //                     libc::EPIPE |
-    //                     libc::EPERM |
-    //                     libc::EIO |
-    //                     libc::EINVAL |
-    //                     libc::EINTR => continue,
-    //                     libc::EFBIG |
-    //                     libc::EFAULT |
-    //                     libc::EDESTADDRREQ |
-    //                     libc::EBADF |
-    //                     libc::EWOULDBLOCK |
-    //                     _ => unreachable!()
-    //                 }
-    //             }
-    //             break;
-    //         }
-    //     }
-    //     type ClCallback = extern "C" fn(cl_event, cl_int, *mut c_void) ;
-    //     let fptr = transmute::<_, ClCallback>(invoker as *mut ());
-    //     let ret_code = clSetEventCallback(
-    //         this.token,
-    //         CL_COMPLETE,
-    //         Some(fptr),
-    //         fd as _
-    //     );
-    //     match ret_code {
-    //         cl_sys::CL_SUCCESS => (),
-    //         cl_sys::CL_OUT_OF_RESOURCES |
-    //         cl_sys::CL_OUT_OF_HOST_MEMORY => {
-    //             return Err(OCLFailure::ResourcesExhausted)
-    //         },
-    //         _ => unreachable!()
-    //     }
-
-    //     return Ok(fd);
-    // } }
+    // Becomes readable once the token completes, for epoll, mio and the like
+    pub fn pollable(&self) -> Result<TokenFd, OCLFailure> { unsafe {
+        let this = &self.0;
+        let mut file_desc = this.file_desc.lock().unwrap();
+        if let Some(fd) = &*file_desc {
+            return Ok(TokenFd(fd.clone()));
+        }
+        let fd = libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK);
+        if fd == -1 {
+            match *libc::__errno_location() {
+                libc::ENOMEM |
+                libc::ENFILE |
+                libc::EMFILE |
+                libc::ENODEV => {
+                    return Err(OCLFailure::ResourcesExhausted);
+                },
+                libc::EINVAL |
+                _ => unreachable!()
+            }
+        }
+        let fd = Arc::new(EventFd(fd));
+        unsafe extern "C" fn invoker(_:cl_event, _:cl_int, ud: *mut ()) {
+            let fd = Arc::from_raw(ud.cast::<EventFd>());
+            let value = 1u64;
+            loop {
+                let outcome = libc::write(fd.0, addr_of!(value).cast(), size_of::<u64>());
+                if outcome == -1 {
+                    match *libc::__errno_location() {
+                        libc::EINTR => continue,
+                        libc::EAGAIN |
+                        libc::EBADF |
+                        libc::EINVAL |
+                        _ => unreachable!()
+                    }
+                }
+                break;
+            }
+        }
+        type ClCallback = extern "C" fn(cl_event, cl_int, *mut c_void) ;
+        let fptr = transmute::<_, ClCallback>(invoker as *mut ());
+        let user_data = Arc::into_raw(fd.clone());
+        let ret_code = clSetEventCallback(
+            this.token,
+            CL_COMPLETE,
+            Some(fptr),
+            user_data.cast_mut().cast()
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                drop(Arc::from_raw(user_data));
+                return Err(OCLFailure::ResourcesExhausted)
+            },
+            _ => unreachable!()
+        }
+        *file_desc = Some(fd.clone());
+        return Ok(TokenFd(fd));
+    } }
     pub fn as_futex(&self) -> Result<&AtomicI32, OCLFailure> { unsafe {
-        let this = &mut *self.0.get();
-        let ptr = addr_of_mut!(this.futex).cast::<AtomicI32>();
-        let fref = &*ptr;
+        let this = &self.0;
+        let fref = &*this.futex;
         match fref.compare_exchange(1, 2, Ordering::Relaxed, Ordering::Relaxed) {
             Ok(_) => (),
             Err(_) => return Ok(fref),
         }
+        // the callback owns a reference, so the token may go away first
         unsafe extern "C" fn invoker(_:cl_event, ret_code:cl_int, ud: *mut AtomicI32) {
-            let futex = &*ud;
+            let futex = Arc::from_raw(ud.cast_const());
             futex.store(ret_code, Ordering::Relaxed);
             libc::syscall(
                 libc::SYS_futex,
-                futex,
+                &*futex,
                 libc::FUTEX_WAKE,
                 u32::MAX,
                 0,
@@ -580,16 +1188,19 @@ impl Token {
         }
         type ClCallback = extern "C" fn(cl_event, cl_int, *mut c_void) ;
         let fptr = transmute::<_, ClCallback>(invoker as *mut ());
+        let user_data = Arc::into_raw(this.futex.clone());
         let ret_code = clSetEventCallback(
             this.token,
             CL_COMPLETE,
             Some(fptr),
-            ptr.cast()
+            user_data.cast_mut().cast()
         );
         match ret_code {
             cl_sys::CL_SUCCESS => (),
             cl_sys::CL_OUT_OF_RESOURCES |
             cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                drop(Arc::from_raw(user_data));
+                fref.store(1, Ordering::Relaxed);
                 return Err(OCLFailure::ResourcesExhausted)
             },
             _ => unreachable!()
@@ -611,23 +1222,39 @@ impl Token {
 }
 impl Drop for Token {
     fn drop(&mut self) { unsafe {
-        let this = &mut *self.0.get();
+        let this = &self.0;
         let _ = clReleaseEvent(this.token);
-        if this.file_desc != -1 {
-            loop {
-                let outcome = libc::close(this.file_desc);
-                if outcome == -1 {
-                    match *libc::__errno_location() {
-                        libc:: EINTR => continue,
-                        libc::EDQUOT |
-                        libc::ENOSPC |
-                        libc::EIO |
-                        libc::EBADF |
-                        _ => unreachable!()
-                    }
+    } }
+}
+// Shared with the completion callback, which may outlive the token
+// Keeps the descriptor open even if the token goes away first
+pub struct TokenFd(Arc<EventFd>);
+impl AsRawFd for TokenFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.0
+    }
+}
+impl AsFd for TokenFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.0.0) }
+    }
+}
+struct EventFd(i32);
+impl Drop for EventFd {
+    fn drop(&mut self) { unsafe {
+        loop {
+            let outcome = libc::close(self.0);
+            if outcome == -1 {
+                match *libc::__errno_location() {
+                    libc:: EINTR => continue,
+                    libc::EDQUOT |
+                    libc::ENOSPC |
+                    libc::EIO |
+                    libc::EBADF |
+                    _ => unreachable!()
                 }
-                break;
             }
+            break;
         }
     } }
 }
@@ -692,22 +1319,54 @@ impl GridDimmensions for (usize,usize,usize) {
         3
     }
 }
+impl GridDimmensions for [usize;1] {
+    fn as_components(&self) -> [size_t;3] {
+        [self[0],0,0]
+    }
+    fn dims(&self) -> u32 {
+        1
+    }
+}
+impl GridDimmensions for [usize;2] {
+    fn as_components(&self) -> [size_t;3] {
+        [self[0],self[1],0]
+    }
+    fn dims(&self) -> u32 {
+        2
+    }
+}
+impl GridDimmensions for [usize;3] {
+    fn as_components(&self) -> [size_t;3] {
+        [self[0],self[1],self[2]]
+    }
+    fn dims(&self) -> u32 {
+        3
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum KernelLaunchFailure {
-    NoMem, InvalidArgs
+    NoMem, InvalidArgs,
+    // a bound buffer was freed or moved since binding
+    ArgumentReleased,
+    ForeignQueue,
+    // bound to memory lent by a scope other than the launching one
+    OutOfScope
 }
 pub struct Device {
     ext: Box<DeviceSpecificExtData>
 }
+unsafe impl Send for Device {}
+unsafe impl Sync for Device {}
 impl Device {
     pub fn allocate_buffer<T>(&self, count: usize) -> Result<MemoryRef<T>, OCLFailure> { unsafe {
         assert!(count > 0, "Item count cannot be zero");
         assert!(self.ext.props.shared_mem_caps.fine_grain_buffer, "No support of fine-grain buffers!");
+        let atomics = self.ext.props.shared_mem_caps.svm_atomics;
         let alloc_props =
             CL_MEM_READ_WRITE |
             CL_MEM_SVM_FINE_GRAIN_BUFFER |
-            if self.ext.props.shared_mem_caps.svm_atomics { CL_MEM_SVM_ATOMICS } else { 0 };
+            if atomics { CL_MEM_SVM_ATOMICS } else { 0 };
         let align =
             align_of::<T>()
             .max(self.ext.props.shared_mem_caps.preffered_platform_atomic_alignment as _);
@@ -722,14 +1381,20 @@ impl Device {
         if ptr == null_mut() {
             return Err(OCLFailure::ResourcesExhausted);
         }
+        register_allocation(ptr, size);
         let ret = MemoryRef {
             ptr: ptr,
             count: count,
+            generation: OCL_SHARED_CONTEXT.generation,
+            atomics: atomics,
             _phantom: PhantomData
         };
         return Ok(ret);
     } }
+    // Slices and already freed memory are not allocations of their own
     pub fn deallocate_memory<T>(&self, memory_ref: MemoryRef<T>) { unsafe {
+        let owned = retire_allocation(memory_ref.ptr);
+        assert!(owned, "Memory at {:?} is not a live allocation", memory_ref.ptr);
         let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
         let () = clSVMFree(ctx, memory_ref.ptr);
     } }
@@ -738,60 +1403,96 @@ impl Device {
         kernel: Kernel,
         grid_dimmensions: impl GridDimmensions,
         dependencies: &[&Token]
-    ) -> Result<Token, KernelLaunchFailure> { unsafe {
+    ) -> Result<Token, KernelLaunchFailure> {
+        self.enqueue_kernel(self.ext.command_queue, kernel, grid_dimmensions, dependencies)
+    }
+    fn enqueue_kernel(
+        &self,
+        queue: cl_command_queue,
+        kernel: Kernel,
+        grid_dimmensions: impl GridDimmensions,
+        dependencies: &[&Token]
+    ) -> Result<Token, KernelLaunchFailure> {
         let grid_dim = grid_dimmensions.dims();
         let dims: [size_t;3] = grid_dimmensions.as_components();
-        let mut completion_token = null_mut();
-        let mut deps = Vec::new();
-        deps.reserve(dependencies.len());
-        for dep in dependencies {
-            let dep = (*dep.0.get()).token;
-            deps.push(dep)
+        // the launch holds its own lease, so the kernel can go right away
+        self.enqueue_kernel_ref(queue, &kernel, grid_dim, dims, dependencies)
+    }
+    pub(crate) fn enqueue_kernel_ref(
+        &self,
+        queue: cl_command_queue,
+        kernel: &Kernel,
+        grid_dim: u32,
+        dims: [size_t;3],
+        dependencies: &[&Token]
+    ) -> Result<Token, KernelLaunchFailure> {
+        if kernel.lent_trackers().next().is_some() {
+            return Err(KernelLaunchFailure::OutOfScope);
         }
-        let (deps_ptr, deps_num) = if deps.is_empty() {
-            (null(), 0)
-        } else {
-            (deps.as_ptr(), deps.len() as u32)
-        };
-        let ret_code = clEnqueueNDRangeKernel(
-            self.ext.command_queue,
-            kernel.handle,
-            grid_dim,
-            null(),
-            dims.as_ptr(),
-            null(),
-            deps_num,
-            deps_ptr,
-            &mut completion_token
-        );
-        match ret_code {
-            cl_sys::CL_SUCCESS => (),
-            cl_sys::CL_OUT_OF_RESOURCES |
-            cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE |
-            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+        self.enqueue_kernel_lent(queue, kernel, grid_dim, dims, dependencies)
+    }
+    // Skips the scope check, the caller vouches for lent bindings
+    pub(crate) fn enqueue_kernel_lent(
+        &self,
+        queue: cl_command_queue,
+        kernel: &Kernel,
+        grid_dim: u32,
+        dims: [size_t;3],
+        dependencies: &[&Token]
+    ) -> Result<Token, KernelLaunchFailure> { unsafe {
+        let bindings = kernel.bindings.iter().chain(&kernel.indirect_bindings);
+        let outcome = enqueue_leased(bindings, dependencies, |deps, event| {
+            let (deps_ptr, deps_num) = if deps.is_empty() {
+                (null(), 0)
+            } else {
+                (deps.as_ptr(), deps.len() as u32)
+            };
+            let ret_code = clEnqueueNDRangeKernel(
+                queue,
+                kernel.handle,
+                grid_dim,
+                null(),
+                dims.as_ptr(),
+                null(),
+                deps_num,
+                deps_ptr,
+                event
+            );
+            if ret_code == CL_SUCCESS {
+                trace::record_launch(queue, kernel.handle, *event, &dims[..grid_dim as usize], deps);
+            }
+            ret_code
+        });
+        match outcome {
+            Ok(tok) => return Ok(tok),
+            Err(EnqueueFailure::Released) => {
+                return Err(KernelLaunchFailure::ArgumentReleased)
+            },
+            Err(EnqueueFailure::ResourcesExhausted) => {
                 return Err(KernelLaunchFailure::NoMem)
             },
-            cl_sys::CL_INVALID_WORK_ITEM_SIZE |
-            cl_sys::CL_INVALID_WORK_GROUP_SIZE |
-            cl_sys::CL_INVALID_GLOBAL_OFFSET |
-            cl_sys::CL_INVALID_GLOBAL_WORK_SIZE |
-            cl_sys::CL_INVALID_KERNEL_ARGS |
-            cl_sys::CL_INVALID_EVENT_WAIT_LIST |
-            cl_sys::CL_INVALID_OPERATION |
-            cl_sys::CL_IMAGE_FORMAT_NOT_SUPPORTED |
-            cl_sys::CL_INVALID_IMAGE_SIZE |
-            cl_sys::CL_MISALIGNED_SUB_BUFFER_OFFSET |
-            cl_sys::CL_INVALID_WORK_DIMENSION => {
-                return Err(KernelLaunchFailure::InvalidArgs)
+            Err(EnqueueFailure::Rejected(ret_code)) => match ret_code {
+                cl_sys::CL_OUT_OF_RESOURCES |
+                cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE |
+                cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                    return Err(KernelLaunchFailure::NoMem)
+                },
+                cl_sys::CL_INVALID_WORK_ITEM_SIZE |
+                cl_sys::CL_INVALID_WORK_GROUP_SIZE |
+                cl_sys::CL_INVALID_GLOBAL_OFFSET |
+                cl_sys::CL_INVALID_GLOBAL_WORK_SIZE |
+                cl_sys::CL_INVALID_KERNEL_ARGS |
+                cl_sys::CL_INVALID_EVENT_WAIT_LIST |
+                cl_sys::CL_INVALID_OPERATION |
+                cl_sys::CL_IMAGE_FORMAT_NOT_SUPPORTED |
+                cl_sys::CL_INVALID_IMAGE_SIZE |
+                cl_sys::CL_MISALIGNED_SUB_BUFFER_OFFSET |
+                cl_sys::CL_INVALID_WORK_DIMENSION => {
+                    return Err(KernelLaunchFailure::InvalidArgs)
+                },
+                _ => unreachable!()
             },
-            _ => unreachable!()
         }
-        let tok = Token(UnsafeCell::new(TokenInner {
-            token: completion_token,
-            file_desc: -1,
-            futex: 1
-        }));
-        return Ok(tok);
     } }
     pub fn get_properties(&self) -> DeviceProps {
         self.ext.props
@@ -800,6 +1501,10 @@ impl Device {
 impl Drop for Device {
     fn drop(&mut self) { unsafe {
         let _ = clReleaseCommandQueue(self.ext.command_queue);
+        let default_device_queue = *self.ext.default_device_queue.get_mut();
+        if !default_device_queue.is_null() {
+            let _ = clReleaseCommandQueue(default_device_queue);
+        }
         let _ = clReleaseDevice(self.ext.handle);
         let prior = OCL_SHARED_CONTEXT.dev_refs.fetch_sub(1, Ordering::Release);
         if prior == 1 {
@@ -814,12 +1519,16 @@ pub struct DeviceProps {
     pub max_work_group_size: usize,
     pub max_alloc_size_in_bytes: usize,
     pub global_mem_size: usize,
+    pub mem_base_addr_align: u32,
     pub shared_mem_caps: DeviceSVMProps,
+    pub device_queue_caps: DeviceQueueProps,
     pub main_queue_is_async: bool,
+    pub main_queue_profiling: bool,
     pub supported_cl_version: (u8,u8)
 }
 struct DeviceSpecificExtData {
     command_queue: cl_command_queue,
+    default_device_queue: AtomicPtr<c_void>,
     handle: cl_device_id,
     props: DeviceProps
 }
@@ -987,6 +1696,7 @@ enum OCLSharedContextInitState {
 }
 struct OCLSharedContext {
     cl_contex: cl_context,
+    generation: u32,
     dev_ids: [cl_device_id; 16],
     dev_refs: AtomicU32,
     len: u32,
@@ -1017,6 +1727,7 @@ impl OCLSharedContext {
 }
 static mut OCL_SHARED_CONTEXT: OCLSharedContext = OCLSharedContext {
     cl_contex: null_mut(),
+    generation: 0,
     dev_ids: [null_mut();_],
     len: 0,
     dev_refs: AtomicU32::new(0),
@@ -1120,6 +1831,14 @@ pub fn enumerate_devices() -> Result<Vec<Device>, OCLFailure> { unsafe {
             addr_of_mut!(global_mem_size).cast(),
             null_mut()
         );
+        let mut base_addr_align_bits = 0u32;
+        ret_c |= clGetDeviceInfo(
+            dev_han,
+            CL_DEVICE_MEM_BASE_ADDR_ALIGN,
+            size_of::<cl_uint>(),
+            addr_of_mut!(base_addr_align_bits).cast(),
+            null_mut()
+        );
         let mut svm_caps = 0;
         ret_c |= clGetDeviceInfo(
             dev_han,
@@ -1169,6 +1888,10 @@ pub fn enumerate_devices() -> Result<Vec<Device>, OCLFailure> { unsafe {
             }
             unreachable!()
         }
+        let device_queue_caps = match device_queue::query_device_queue_props(dev_han) {
+            Ok(caps) => caps,
+            Err(_) => return Err(OCLFailure::ResourcesExhausted),
+        };
         let svm_caps = DeviceSVMProps {
             fine_grain_buffer: svm_caps & CL_DEVICE_SVM_FINE_GRAIN_BUFFER != 0,
             fine_grain_system: svm_caps & CL_DEVICE_SVM_FINE_GRAIN_SYSTEM != 0,
@@ -1181,13 +1904,17 @@ pub fn enumerate_devices() -> Result<Vec<Device>, OCLFailure> { unsafe {
             max_work_group_size: wg_max_size,
             max_alloc_size_in_bytes: max_alloc_size,
             global_mem_size: global_mem_size,
+            mem_base_addr_align: base_addr_align_bits / 8,
             shared_mem_caps: svm_caps,
+            device_queue_caps: device_queue_caps,
             main_queue_is_async: false,
+            main_queue_profiling: false,
             supported_cl_version: cl_version
         };
         let dev_ext = DeviceSpecificExtData {
             handle: dev_han,
             command_queue: null_mut(),
+            default_device_queue: AtomicPtr::new(null_mut()),
             props: props
         };
         let dev = Device {
@@ -1222,47 +1949,89 @@ pub fn enumerate_devices() -> Result<Vec<Device>, OCLFailure> { unsafe {
         }
     }
     OCL_SHARED_CONTEXT.cl_contex = cl_ctx;
+    OCL_SHARED_CONTEXT.generation += 1;
     for dev in &mut devs {
-        let mut ret_code = CL_SUCCESS;
-        let q = clCreateCommandQueue(
+        let (q, cmd_q_props) = match make_command_queue(
             cl_ctx,
             dev.ext.handle,
             CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE,
+            &[]
+        ) {
+            Ok(queue) => queue,
+            Err(_) => return Err(OCLFailure::ResourcesExhausted),
+        };
+        let device_q_is_async = cmd_q_props & CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE != 0;
+        dev.ext.props.main_queue_is_async = device_q_is_async;
+        dev.ext.command_queue = q;
+    }
+
+    OCL_SHARED_CONTEXT.state.store(OCLSharedContextInitState::DoneInit as _, Ordering::Release);
+
+    return Ok(devs)
+} }
+
+pub(crate) enum QueueCreationFailure {
+    ResourcesExhausted,
+    // an extra property was rejected
+    InvalidProperties
+}
+// Returns the queue along with the properties it actually got.
+// Devices rejecting out-of-order execution get an in-order queue instead.
+fn make_command_queue(
+    cl_ctx: cl_context,
+    device: cl_device_id,
+    props: cl_command_queue_properties,
+    extra_props: &[cl_queue_properties]
+) -> Result<(cl_command_queue, cl_command_queue_properties), QueueCreationFailure> { unsafe {
+    let mut props = props;
+    let q = loop {
+        let mut prop_list = vec![CL_QUEUE_PROPERTIES as cl_queue_properties, props];
+        prop_list.extend_from_slice(extra_props);
+        prop_list.push(0);
+        let mut ret_code = CL_SUCCESS;
+        let q = clCreateCommandQueueWithProperties(
+            cl_ctx,
+            device,
+            prop_list.as_ptr(),
             &mut ret_code
         );
         match ret_code {
-            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_SUCCESS => break q,
             cl_sys::CL_OUT_OF_RESOURCES |
             cl_sys::CL_OUT_OF_HOST_MEMORY => {
-                return Err(OCLFailure::ResourcesExhausted)
+                return Err(QueueCreationFailure::ResourcesExhausted)
             },
-            cl_sys::CL_INVALID_QUEUE_PROPERTIES |
-            _ => unreachable!()
-        }
-        let mut cmd_q_props: cl_bitfield = 0;
-        let ret_code = clGetCommandQueueInfo(
-            q,
-            CL_QUEUE_PROPERTIES,
-            size_of::<cl_command_queue_properties>(),
-            addr_of_mut!(cmd_q_props).cast(),
-            null_mut()
-        );
-        match ret_code {
-            cl_sys::CL_SUCCESS => (),
-            cl_sys::CL_OUT_OF_RESOURCES |
-            cl_sys::CL_OUT_OF_HOST_MEMORY => {
-                return Err(OCLFailure::ResourcesExhausted)
+            cl_sys::CL_INVALID_QUEUE_PROPERTIES
+                if props & CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE != 0 => {
+                props &= !CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE;
+                continue;
+            },
+            cl_sys::CL_INVALID_VALUE if !extra_props.is_empty() => {
+                return Err(QueueCreationFailure::InvalidProperties)
             },
+            cl_sys::CL_INVALID_QUEUE_PROPERTIES |
+            cl_sys::CL_INVALID_VALUE |
             _ => unreachable!()
         }
-        let device_q_is_async = cmd_q_props & CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE != 0;
-        dev.ext.props.main_queue_is_async = device_q_is_async;
-        dev.ext.command_queue = q;
+    };
+    let mut cmd_q_props: cl_bitfield = 0;
+    let ret_code = clGetCommandQueueInfo(
+        q,
+        CL_QUEUE_PROPERTIES,
+        size_of::<cl_command_queue_properties>(),
+        addr_of_mut!(cmd_q_props).cast(),
+        null_mut()
+    );
+    match ret_code {
+        cl_sys::CL_SUCCESS => (),
+        cl_sys::CL_OUT_OF_RESOURCES |
+        cl_sys::CL_OUT_OF_HOST_MEMORY => {
+            let _ = clReleaseCommandQueue(q);
+            return Err(QueueCreationFailure::ResourcesExhausted)
+        },
+        _ => unreachable!()
     }
-
-    OCL_SHARED_CONTEXT.state.store(OCLSharedContextInitState::DoneInit as _, Ordering::Release);
-
-    return Ok(devs)
+    return Ok((q, cmd_q_props));
 } }
 
 #[test]
@@ -1304,56 +2073,105 @@ fn kernel_names() {
         println!("{}", name)
     }
 }
-// #[test]
-// fn ops_on_fd() {
-//     let devs = enumerate_devices().unwrap();
-//     let dev = &devs[0];
-
-//     let item_count = 65535;
-//     let mut mem = dev.allocate_memory::<u32>(item_count).unwrap();
-//     let mut ix = 0;
-//     for item in mem.as_mut_items() {
-//         *item = ix;
-//         ix += 1;
-//     }
-
-//     let text = r#"
-//     __kernel void lol(__global uint* param1, uint param2) {
-//         uint gix = get_global_id(0);
-//         param1[gix] *= 2;
-//     }"#;
-
-//     let bundle = CodeBundle::from_textual_reprs(&[
-//         text.as_bytes()
-//     ]).unwrap();
-
-//     let param = 2u32;
-//     let kern = bundle.build_kernel("lol", (mem, param,)).unwrap();
-
-//     let tok = dev.launch_kernel(kern, item_count, ).unwrap();
-
-//     let fd = tok.as_fd().unwrap();
-
-//     let mut evs = [(fd, libc::POLLIN, 0i16)];
-//     loop {
-//         let ret_code = unsafe { libc::poll(evs.as_mut_ptr().cast(), 1, -1) };
-//         if ret_code == -1 {
-//             unsafe { panic!("Failed {}", *libc::__errno_location()) }
-//         }
-
-//         let mut buf = [0u8;4];
-//         unsafe { libc::read(fd, buf.as_mut_ptr().cast(), 4) };
-//         let str = unsafe { core::str::from_utf8_unchecked(&buf) };
-//         if str == "done" { break }
-//     }
-
-//     let mut ix = 0;
-//     for i in mem.as_items() {
-//         let k = ix * 2;
-//         assert!(*i == k);
-//         ix += 1;
-//     }
-// }
+#[test]
+fn ops_on_fd() {
+    let devs = enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let item_count = 65535;
+    let mut mem = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let mut ix = 0;
+    for item in mem.as_mut_items() {
+        *item = ix;
+        ix += 1;
+    }
+
+    let text = r#"
+    __kernel void lol(__global uint* param1, uint param2) {
+        uint gix = get_global_id(0);
+        param1[gix] *= 2;
+    }"#;
+
+    let bundle = CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+
+    let param = 2u32;
+    let kern = bundle.instantiate_kernel("lol", (mem, param,)).unwrap();
+
+    let tok = dev.launch_kernel(kern, (item_count,), &[]).unwrap();
+
+    let fd = tok.pollable().unwrap();
+
+    let mut evs = [libc::pollfd { fd: fd.as_fd().as_raw_fd(), events: libc::POLLIN, revents: 0 }];
+    loop {
+        let ret_code = unsafe { libc::poll(evs.as_mut_ptr(), 1, -1) };
+        if ret_code == -1 {
+            unsafe { panic!("Failed {}", *libc::__errno_location()) }
+        }
+        if evs[0].revents & libc::POLLIN != 0 { break }
+    }
+
+    let mut ix = 0;
+    for i in mem.as_items() {
+        let k = ix * 2;
+        assert!(*i == k);
+        ix += 1;
+    }
+}
+
+#[test]
+fn threaded_launches() {
+    fn assert_thread_safe<T: Send + Sync>() {}
+    assert_thread_safe::<Device>();
+    assert_thread_safe::<CodeBundle>();
+    assert_thread_safe::<Kernel>();
+    assert_thread_safe::<Token>();
+    assert_thread_safe::<UserToken>();
+    assert_thread_safe::<SvmVec<u32>>();
+
+    let devs = enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let text = r#"
+    __kernel void lol(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] += 1;
+    }"#;
+    let bundle = CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let gate = UserToken::new().unwrap();
+
+    let thread_count = 8;
+    let launch_count = 64;
+    let item_count = 4096;
+    std::thread::scope(|scope| {
+        for _ in 0 .. thread_count {
+            scope.spawn(|| {
+                let mut mem = dev.allocate_buffer::<u32>(item_count).unwrap();
+                for item in mem.as_mut_items() {
+                    *item = 0;
+                }
+                let mut last: Option<Token> = None;
+                for _ in 0 .. launch_count {
+                    let kern = bundle.instantiate_kernel("lol", (mem,)).unwrap();
+                    let deps = match &last {
+                        Some(tok) => vec![tok],
+                        None => vec![&*gate],
+                    };
+                    last = Some(dev.launch_kernel(kern, (item_count,), &deps).unwrap());
+                }
+                let _ = gate.pollable().unwrap();
+                last.unwrap().await_completion().unwrap();
+                assert!(mem.as_items().iter().all(|i| *i == launch_count));
+                dev.deallocate_memory(mem);
+            });
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        gate.complete().unwrap();
+    });
+}
 
 #[test]
 fn ops_on_cb() {
@@ -1383,12 +2201,20 @@ fn ops_on_cb() {
 
     let tok = dev.launch_kernel(kern, (item_count,), &[]).unwrap();
 
-    let done = core::sync::atomic::AtomicBool::new(false);
-    tok.attach_completion_callback(|_|{
-        done.store(true, Ordering::Relaxed);
+    let started = Arc::new(core::sync::atomic::AtomicBool::new(false));
+    let done = Arc::new(core::sync::atomic::AtomicBool::new(false));
+    let flag = started.clone();
+    tok.attach_callback(CallbackState::Running, move |state|{
+        assert!(matches!(state, Ok(ExecutionState::Running)));
+        flag.store(true, Ordering::Relaxed);
+    }).unwrap();
+    let flag = done.clone();
+    tok.attach_completion_callback(move |state|{
+        assert!(matches!(state, Ok(ExecutionState::Complete)));
+        flag.store(true, Ordering::Release);
     }).unwrap();
 
-    while !done.load(Ordering::Relaxed) {}
+    while !done.load(Ordering::Acquire) || !started.load(Ordering::Relaxed) {}
 
     // println!("{:#?}", mem.as_items());
 
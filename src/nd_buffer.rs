@@ -0,0 +1,279 @@
+use core::{any::TypeId, mem::{align_of_val, size_of, size_of_val, transmute}, ops::{Index, IndexMut, Range}, ptr::{addr_of, drop_in_place}};
+use std::sync::Arc;
+
+use cl_sys::clSVMFree;
+
+use crate::{allocation_tracker, retire_allocation, va_args_emu::{self, ErasedRef}, BufferTracker, Device, MemoryRef, SomeMemoryRef, SvmRegion, OCL_SHARED_CONTEXT};
+
+// Axis 0 is the fastest varying one, same as get_global_id(0),
+// so a shape maps onto a launch grid without transposition.
+fn contiguous_strides<const D: usize>(shape: [usize; D]) -> [usize; D] {
+    let mut strides = [0; D];
+    let mut step = 1;
+    for axis in 0 .. D {
+        strides[axis] = step;
+        step *= shape[axis];
+    }
+    return strides;
+}
+fn offset_of<const D: usize>(
+    index: [usize; D],
+    shape: &[usize; D],
+    strides: &[usize; D]
+) -> usize {
+    let mut offset = 0;
+    for axis in 0 .. D {
+        assert!(index[axis] < shape[axis], "Index {:?} is out of shape {:?}", index, shape);
+        offset += index[axis] * strides[axis];
+    }
+    return offset;
+}
+fn advance<const D: usize>(index: &mut [usize; D], shape: &[usize; D]) -> bool {
+    for axis in 0 .. D {
+        index[axis] += 1;
+        if index[axis] < shape[axis] { return true }
+        index[axis] = 0;
+    }
+    return false;
+}
+
+#[derive(Debug, Clone, Copy)] #[repr(C)]
+pub struct NdDims([u64; 4]);
+impl va_args_emu::KernelArgument for NdDims {
+    fn as_opaque(&self) -> ErasedRef {
+        ErasedRef {
+            data_ptr: addr_of!(*self).cast(),
+            size: size_of_val(self),
+            alignment: align_of_val(self),
+            type_id: TypeId::of::<Self>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum NdBufferFailure {
+    ResourcesExhausted,
+    EmptyAxis(usize),
+    SizeOverflow
+}
+
+#[repr(C)]
+pub struct NdBuffer<T, const D: usize> {
+    pub(crate) mem: MemoryRef<T>,
+    shape: [usize; D],
+    strides: [usize; D],
+    tracker: Arc<BufferTracker>
+}
+impl<T, const D: usize> NdBuffer<T, D> {
+    pub fn shape(&self) -> [usize; D] { self.shape }
+    pub fn strides(&self) -> [usize; D] { self.strides }
+    pub fn len(&self) -> usize { self.mem.count }
+    pub fn grid(&self) -> [usize; D] { self.shape }
+    pub fn dims(&self) -> NdDims {
+        let mut dims = [1u64; 4];
+        for axis in 0 .. D {
+            dims[axis] = self.shape[axis] as u64;
+        }
+        NdDims(dims)
+    }
+    // The own tracker is the one of the allocation, so the memory
+    // is read directly instead of waiting a second time through `mem`
+    pub fn as_items(&self) -> &[T] {
+        self.tracker.await_host_access(false);
+        unsafe { core::slice::from_raw_parts(self.mem.ptr.cast(), self.mem.count) }
+    }
+    pub fn as_mut_items(&mut self) -> &mut [T] {
+        self.tracker.await_host_access(true);
+        unsafe { core::slice::from_raw_parts_mut(self.mem.ptr.cast(), self.mem.count) }
+    }
+    pub fn set_dependency_tracking(&self, enabled: bool) {
+        self.tracker.set_tracking(enabled)
+    }
+    pub fn get(&self, index: [usize; D]) -> Option<&T> {
+        for axis in 0 .. D {
+            if index[axis] >= self.shape[axis] { return None }
+        }
+        Some(&self[index])
+    }
+    pub fn slice(&self, ranges: [Range<usize>; D]) -> NdView<'_, T, D> {
+        self.view().slice(ranges)
+    }
+    pub fn view(&self) -> NdView<'_, T, D> {
+        NdView {
+            items: self.as_items(),
+            offset: 0,
+            shape: self.shape,
+            strides: self.strides
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = ([usize; D], &T)> {
+        self.view().iter()
+    }
+}
+impl<T, const D: usize> Index<[usize; D]> for NdBuffer<T, D> {
+    type Output = T;
+    fn index(&self, index: [usize; D]) -> &T {
+        let offset = offset_of(index, &self.shape, &self.strides);
+        &self.as_items()[offset]
+    }
+}
+impl<T, const D: usize> IndexMut<[usize; D]> for NdBuffer<T, D> {
+    fn index_mut(&mut self, index: [usize; D]) -> &mut T {
+        let offset = offset_of(index, &self.shape, &self.strides);
+        &mut self.as_mut_items()[offset]
+    }
+}
+impl<T, const D: usize> Drop for NdBuffer<T, D> {
+    fn drop(&mut self) { unsafe {
+        retire_allocation(self.mem.ptr);
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        clSVMFree(ctx, self.mem.ptr);
+    } }
+}
+impl<T, const D: usize> SvmRegion for NdBuffer<T, D> {
+    fn svm_base_ptr(&self) -> *mut cl_sys::c_void { self.mem.ptr }
+    fn context_generation(&self) -> u32 { self.mem.generation }
+    fn tracker(&self) -> Option<Arc<BufferTracker>> { Some(self.tracker.clone()) }
+}
+impl<T, const D: usize> va_args_emu::KernelArgument for &NdBuffer<T, D> {
+    fn as_opaque(&self) -> ErasedRef {
+        ErasedRef {
+            data_ptr: addr_of!(**self).cast(),
+            size: size_of_val(*self),
+            alignment: align_of_val(*self),
+            type_id: TypeId::of::<SomeMemoryRef>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: Some(self.tracker.clone())
+        }
+    }
+}
+
+pub struct NdView<'a, T, const D: usize> {
+    items: &'a [T],
+    offset: usize,
+    shape: [usize; D],
+    strides: [usize; D]
+}
+impl<'a, T, const D: usize> Clone for NdView<'a, T, D> {
+    fn clone(&self) -> Self { *self }
+}
+impl<'a, T, const D: usize> Copy for NdView<'a, T, D> {}
+impl<'a, T, const D: usize> NdView<'a, T, D> {
+    pub fn shape(&self) -> [usize; D] { self.shape }
+    pub fn strides(&self) -> [usize; D] { self.strides }
+    pub fn get(&self, index: [usize; D]) -> Option<&'a T> {
+        for axis in 0 .. D {
+            if index[axis] >= self.shape[axis] { return None }
+        }
+        let offset = offset_of(index, &self.shape, &self.strides);
+        Some(&self.items[self.offset + offset])
+    }
+    pub fn slice(&self, ranges: [Range<usize>; D]) -> NdView<'a, T, D> {
+        let mut shape = [0; D];
+        let mut offset = self.offset;
+        for axis in 0 .. D {
+            let range = &ranges[axis];
+            assert!(
+                range.start <= range.end && range.end <= self.shape[axis],
+                "Range {:?} is out of axis {} of length {}", range, axis, self.shape[axis]
+            );
+            shape[axis] = range.end - range.start;
+            offset += range.start * self.strides[axis];
+        }
+        NdView {
+            items: self.items,
+            offset: offset,
+            shape: shape,
+            strides: self.strides
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = ([usize; D], &'a T)> {
+        let view = *self;
+        let mut index = [0; D];
+        let mut done = self.shape.iter().any(|len| *len == 0);
+        core::iter::from_fn(move || {
+            if done { return None }
+            let current = index;
+            let item = view.get(current).unwrap();
+            done = !advance(&mut index, &view.shape);
+            return Some((current, item));
+        })
+    }
+}
+impl<'a, T, const D: usize> Index<[usize; D]> for NdView<'a, T, D> {
+    type Output = T;
+    fn index(&self, index: [usize; D]) -> &T {
+        let offset = offset_of(index, &self.shape, &self.strides);
+        &self.items[self.offset + offset]
+    }
+}
+
+impl Device {
+    pub fn allocate_nd_buffer<T, const D: usize>(
+        &self,
+        shape: [usize; D]
+    ) -> Result<NdBuffer<T, D>, NdBufferFailure> {
+        const { assert!(D >= 1 && D <= 3, "Only 1, 2 and 3 dimensional buffers map onto a launch grid") };
+        let mut count = 1usize;
+        for (axis, len) in shape.iter().enumerate() {
+            if *len == 0 { return Err(NdBufferFailure::EmptyAxis(axis)) }
+            count = match count.checked_mul(*len) {
+                Some(count) => count,
+                None => return Err(NdBufferFailure::SizeOverflow),
+            };
+        }
+        if count.checked_mul(size_of::<T>()).is_none() {
+            return Err(NdBufferFailure::SizeOverflow);
+        }
+        let mem = match self.allocate_buffer::<T>(count) {
+            Ok(mem) => mem,
+            Err(_) => return Err(NdBufferFailure::ResourcesExhausted),
+        };
+        let val = NdBuffer {
+            mem: mem,
+            shape: shape,
+            strides: contiguous_strides(shape),
+            tracker: allocation_tracker(mem.ptr).unwrap()
+        };
+        return Ok(val);
+    }
+}
+
+#[test]
+fn nd_indexing() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    match dev.allocate_nd_buffer::<u32, 2>([64, 0]) {
+        Err(NdBufferFailure::EmptyAxis(1)) => (),
+        _ => panic!("Empty axis was allocated")
+    }
+    let mut image = dev.allocate_nd_buffer::<u32, 2>([64, 32]).unwrap();
+    for y in 0 .. 32 {
+        for x in 0 .. 64 {
+            image[[x, y]] = (y * 64 + x) as u32;
+        }
+    }
+
+    let text = r#"
+    __kernel void lol(__global uint* image, ulong4 dims) {
+        size_t x = get_global_id(0);
+        size_t y = get_global_id(1);
+        image[y * dims.x + x] *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let kern = bundle.instantiate_kernel("lol", (&image, image.dims(),)).unwrap();
+    let tok = dev.launch_kernel(kern, image.grid(), &[]).unwrap();
+    tok.await_completion().unwrap();
+
+    let window = image.slice([8 .. 16, 4 .. 6]);
+    assert!(window.shape() == [8, 2]);
+    assert!(window[[0, 0]] == (4 * 64 + 8) * 2);
+    for (ix, item) in image.iter() {
+        assert!(*item == ((ix[1] * 64 + ix[0]) * 2) as u32);
+    }
+}
@@ -0,0 +1,189 @@
+use core::{any::TypeId, marker::PhantomData, mem::{align_of_val, size_of, transmute}, ptr::{addr_of, addr_of_mut, drop_in_place, null, null_mut}};
+
+use cl_sys::{clCreatePipe, clGetPipeInfo, clReleaseMemObject, cl_mem, cl_uint, CL_MEM_READ_WRITE, CL_PIPE_MAX_PACKETS, CL_PIPE_PACKET_SIZE, CL_SUCCESS};
+
+use crate::{va_args_emu::{self, ErasedRef}, OCL_SHARED_CONTEXT};
+
+#[derive(Debug, Clone, Copy)]
+pub enum PipeFailure {
+    ResourcesExhausted,
+    InvalidSize
+}
+pub(crate) fn pipe_info(handle: cl_mem, param: cl_uint) -> Result<cl_uint, PipeFailure> { unsafe {
+    let mut value: cl_uint = 0;
+    let ret_code = clGetPipeInfo(
+        handle,
+        param,
+        size_of::<cl_uint>(),
+        addr_of_mut!(value).cast(),
+        null_mut()
+    );
+    match ret_code {
+        cl_sys::CL_SUCCESS => (),
+        cl_sys::CL_OUT_OF_RESOURCES |
+        cl_sys::CL_OUT_OF_HOST_MEMORY => {
+            return Err(PipeFailure::ResourcesExhausted)
+        },
+        _ => unreachable!()
+    }
+    return Ok(value);
+} }
+// Packet size of a builtin scalar or vector type as named by the kernel
+// argument info, None for user defined types
+pub(crate) fn packet_size_of(type_name: &str) -> Option<usize> {
+    let name = type_name.trim_end_matches('\0');
+    let digits = name.len() - name.bytes().rev().take_while(u8::is_ascii_digit).count();
+    let (scalar, width) = name.split_at(digits);
+    let width = match width {
+        "" => 1,
+        "2" => 2,
+        "3" | "4" => 4,
+        "8" => 8,
+        "16" => 16,
+        _ => return None
+    };
+    let scalar_size = match scalar {
+        "char" | "uchar" | "unsigned char" => 1,
+        "short" | "ushort" | "unsigned short" | "half" => 2,
+        "int" | "uint" | "unsigned int" | "float" => 4,
+        "long" | "ulong" | "unsigned long" | "double" => 8,
+        _ => return None
+    };
+    return Some(scalar_size * width);
+}
+pub(crate) struct SomePipeReadEnd {}
+pub(crate) struct SomePipeWriteEnd {}
+
+pub struct Pipe<T> {
+    handle: cl_mem,
+    _phantom: PhantomData<T>
+}
+// Only kernels touch the packets
+unsafe impl<T: Send> Send for Pipe<T> {}
+unsafe impl<T: Send> Sync for Pipe<T> {}
+impl<T> Pipe<T> {
+    pub fn new(capacity: u32) -> Result<Pipe<T>, PipeFailure> { unsafe {
+        assert!(capacity > 0, "Pipe capacity cannot be zero");
+        let packet_size = size_of::<T>();
+        assert!(packet_size > 0, "Zero sized packets cannot be sent over a pipe");
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        let mut ret_code = CL_SUCCESS;
+        let handle = clCreatePipe(
+            ctx,
+            CL_MEM_READ_WRITE,
+            packet_size as cl_uint,
+            capacity,
+            null(),
+            &mut ret_code
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY |
+            cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE => {
+                return Err(PipeFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_PIPE_SIZE => {
+                return Err(PipeFailure::InvalidSize)
+            },
+            cl_sys::CL_INVALID_CONTEXT |
+            cl_sys::CL_INVALID_VALUE |
+            _ => unreachable!()
+        }
+        let val = Pipe {
+            handle: handle,
+            _phantom: PhantomData
+        };
+        return Ok(val);
+    } }
+    pub fn packet_size(&self) -> Result<u32, PipeFailure> {
+        pipe_info(self.handle, CL_PIPE_PACKET_SIZE)
+    }
+    pub fn max_packets(&self) -> Result<u32, PipeFailure> {
+        pipe_info(self.handle, CL_PIPE_MAX_PACKETS)
+    }
+    pub fn read_end(&self) -> PipeReadEnd<'_, T> {
+        PipeReadEnd(self)
+    }
+    pub fn write_end(&self) -> PipeWriteEnd<'_, T> {
+        PipeWriteEnd(self)
+    }
+}
+impl<T> Drop for Pipe<T> {
+    fn drop(&mut self) {
+        let _ = unsafe { clReleaseMemObject(self.handle) };
+    }
+}
+pub struct PipeReadEnd<'a, T>(&'a Pipe<T>);
+pub struct PipeWriteEnd<'a, T>(&'a Pipe<T>);
+impl<'a, T> va_args_emu::KernelArgument for PipeReadEnd<'a, T> {
+    fn as_opaque(&self) -> ErasedRef {
+        ErasedRef {
+            data_ptr: addr_of!(self.0.handle).cast(),
+            size: size_of::<cl_mem>(),
+            alignment: align_of_val(&self.0.handle),
+            type_id: TypeId::of::<SomePipeReadEnd>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: None
+        }
+    }
+}
+impl<'a, T> va_args_emu::KernelArgument for PipeWriteEnd<'a, T> {
+    fn as_opaque(&self) -> ErasedRef {
+        ErasedRef {
+            data_ptr: addr_of!(self.0.handle).cast(),
+            size: size_of::<cl_mem>(),
+            alignment: align_of_val(&self.0.handle),
+            type_id: TypeId::of::<SomePipeWriteEnd>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: None
+        }
+    }
+}
+
+#[test]
+fn producer_consumer() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let item_count = 1024;
+    let pipe = Pipe::<u32>::new(item_count as u32).unwrap();
+    assert!(pipe.packet_size().unwrap() == 4);
+    let out = dev.allocate_buffer::<u32>(item_count).unwrap();
+
+    let text = r#"
+    __kernel void produce(write_only pipe uint p) {
+        uint gix = get_global_id(0);
+        write_pipe(p, &gix);
+    }
+    __kernel void consume(read_only pipe uint p, __global uint* out) {
+        uint gix = get_global_id(0);
+        uint val;
+        read_pipe(p, &val);
+        out[val] = val * 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    match bundle.instantiate_kernel("produce", (pipe.read_end(),)) {
+        Err(crate::KernelCreationFailure::ArgAccessMismatch(0)) => (),
+        _ => panic!("Access qualifier was not checked")
+    }
+    let wide = Pipe::<u64>::new(item_count as u32).unwrap();
+    match bundle.instantiate_kernel("produce", (wide.write_end(),)) {
+        Err(crate::KernelCreationFailure::ArgTypeMismatch(0)) => (),
+        _ => panic!("Packet size was not checked")
+    }
+    let producer = bundle.instantiate_kernel("produce", (pipe.write_end(),)).unwrap();
+    let consumer = bundle.instantiate_kernel("consume", (pipe.read_end(), out,)).unwrap();
+    let tok1 = dev.launch_kernel(producer, (item_count,), &[]).unwrap();
+    let tok2 = dev.launch_kernel(consumer, (item_count,), &[&tok1]).unwrap();
+    tok2.await_completion().unwrap();
+
+    let mut ix = 0;
+    for i in out.as_items() {
+        assert!(*i == ix * 2);
+        ix += 1;
+    }
+    dev.deallocate_memory(out);
+}
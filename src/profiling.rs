@@ -0,0 +1,184 @@
+use core::{mem::size_of, ptr::{addr_of_mut, null_mut}, time::Duration};
+
+use cl_sys::{clFinish, clGetEventProfilingInfo, clReleaseCommandQueue, cl_ulong, CL_PROFILING_COMMAND_COMPLETE, CL_PROFILING_COMMAND_END, CL_PROFILING_COMMAND_QUEUED, CL_PROFILING_COMMAND_START, CL_PROFILING_COMMAND_SUBMIT, CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE, CL_QUEUE_PROFILING_ENABLE};
+
+use crate::{make_command_queue, Device, OCLFailure, Token, OCL_SHARED_CONTEXT};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ProfilingFailure {
+    ResourcesExhausted,
+    NotAvailable
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LaunchProfile {
+    pub queued: Duration,
+    pub submitted: Duration,
+    pub started: Duration,
+    pub ended: Duration,
+    pub completed: Duration
+}
+impl LaunchProfile {
+    pub fn execution_time(&self) -> Duration {
+        self.ended.saturating_sub(self.started)
+    }
+    pub fn queueing_delay(&self) -> Duration {
+        self.started.saturating_sub(self.queued)
+    }
+}
+
+impl Token {
+    fn profiling_value(&self, param: u32) -> Result<Duration, ProfilingFailure> { unsafe {
+        let this = &self.0;
+        let mut value: cl_ulong = 0;
+        let ret_code = clGetEventProfilingInfo(
+            this.token,
+            param,
+            size_of::<cl_ulong>(),
+            addr_of_mut!(value).cast(),
+            null_mut()
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                return Err(ProfilingFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_PROFILING_INFO_NOT_AVAILABLE => {
+                return Err(ProfilingFailure::NotAvailable)
+            },
+            cl_sys::CL_INVALID_VALUE |
+            cl_sys::CL_INVALID_EVENT |
+            _ => unreachable!()
+        }
+        return Ok(Duration::from_nanos(value));
+    } }
+    pub fn profile(&self) -> Result<LaunchProfile, ProfilingFailure> {
+        let val = LaunchProfile {
+            queued: self.profiling_value(CL_PROFILING_COMMAND_QUEUED)?,
+            submitted: self.profiling_value(CL_PROFILING_COMMAND_SUBMIT)?,
+            started: self.profiling_value(CL_PROFILING_COMMAND_START)?,
+            ended: self.profiling_value(CL_PROFILING_COMMAND_END)?,
+            completed: self.profiling_value(CL_PROFILING_COMMAND_COMPLETE)?
+        };
+        return Ok(val);
+    }
+}
+
+impl Device {
+    pub fn set_profiling(&mut self, enabled: bool) -> Result<(), OCLFailure> { unsafe {
+        if self.ext.props.main_queue_profiling == enabled {
+            return Ok(());
+        }
+        let props =
+            CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE |
+            if enabled { CL_QUEUE_PROFILING_ENABLE } else { 0 };
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        let (q, actual_props) = match make_command_queue(ctx, self.ext.handle, props, &[]) {
+            Ok(queue) => queue,
+            Err(_) => return Err(OCLFailure::ResourcesExhausted),
+        };
+        let _ = clFinish(self.ext.command_queue);
+        let _ = clReleaseCommandQueue(self.ext.command_queue);
+        self.ext.command_queue = q;
+        self.ext.props.main_queue_is_async = actual_props & CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE != 0;
+        self.ext.props.main_queue_profiling = actual_props & CL_QUEUE_PROFILING_ENABLE != 0;
+        return Ok(());
+    } }
+}
+
+// cl-sys declares this one with timestamps passed by value
+#[cfg(feature = "opencl_2_1")]
+extern "system" {
+    fn clGetDeviceAndHostTimer(
+        device: cl_sys::cl_device_id,
+        device_timestamp: *mut cl_ulong,
+        host_timestamp: *mut cl_ulong
+    ) -> cl_sys::cl_int;
+}
+
+#[cfg(feature = "opencl_2_1")]
+#[derive(Debug, Clone, Copy)]
+pub struct TimerSync {
+    pub device: Duration,
+    pub host: Duration
+}
+#[cfg(feature = "opencl_2_1")]
+impl TimerSync {
+    pub fn to_host_time(&self, device_time: Duration) -> Duration {
+        if device_time >= self.device {
+            self.host + (device_time - self.device)
+        } else {
+            self.host.saturating_sub(self.device - device_time)
+        }
+    }
+}
+#[cfg(feature = "opencl_2_1")]
+pub(crate) fn device_and_host_timer(device: cl_sys::cl_device_id) -> Result<TimerSync, ProfilingFailure> { unsafe {
+    let mut device_time: cl_ulong = 0;
+    let mut host_time: cl_ulong = 0;
+    let ret_code = clGetDeviceAndHostTimer(
+        device,
+        &mut device_time,
+        &mut host_time
+    );
+    match ret_code {
+        cl_sys::CL_SUCCESS => (),
+        cl_sys::CL_OUT_OF_RESOURCES |
+        cl_sys::CL_OUT_OF_HOST_MEMORY => {
+            return Err(ProfilingFailure::ResourcesExhausted)
+        },
+        cl_sys::CL_INVALID_OPERATION => {
+            return Err(ProfilingFailure::NotAvailable)
+        },
+        cl_sys::CL_INVALID_DEVICE |
+        cl_sys::CL_INVALID_VALUE |
+        _ => unreachable!()
+    }
+    let val = TimerSync {
+        device: Duration::from_nanos(device_time),
+        host: Duration::from_nanos(host_time)
+    };
+    return Ok(val);
+} }
+#[cfg(feature = "opencl_2_1")]
+impl Device {
+    pub fn sync_timers(&self) -> Result<TimerSync, ProfilingFailure> {
+        device_and_host_timer(self.ext.handle)
+    }
+}
+
+#[test]
+fn kernel_timing() {
+    let mut devs = crate::enumerate_devices().unwrap();
+    let dev = &mut devs[0];
+    dev.set_profiling(true).unwrap();
+    assert!(dev.get_properties().main_queue_profiling);
+
+    let item_count = 65535;
+    let mem = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let text = r#"
+    __kernel void lol(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] = gix;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let kern = bundle.instantiate_kernel("lol", (mem,)).unwrap();
+    let tok = dev.launch_kernel(kern, (item_count,), &[]).unwrap();
+    tok.await_completion().unwrap();
+
+    let profile = tok.profile().unwrap();
+    assert!(profile.queued <= profile.submitted);
+    assert!(profile.submitted <= profile.started);
+    assert!(profile.started <= profile.ended);
+    assert!(profile.ended <= profile.completed);
+
+    dev.set_profiling(false).unwrap();
+    let kern = bundle.instantiate_kernel("lol", (mem,)).unwrap();
+    let tok = dev.launch_kernel(kern, (item_count,), &[]).unwrap();
+    tok.await_completion().unwrap();
+    assert!(matches!(tok.profile(), Err(ProfilingFailure::NotAvailable)));
+    dev.deallocate_memory(mem);
+}
@@ -0,0 +1,224 @@
+use core::{mem::size_of, ptr::null_mut};
+
+use cl_sys::{clFinish, clFlush, clGetDeviceInfo, clReleaseCommandQueue, cl_command_queue, cl_device_id, cl_queue_properties, CL_DEVICE_EXTENSIONS, CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE, CL_QUEUE_PROFILING_ENABLE};
+
+use crate::{make_command_queue, sync_points::queue_op, QueueCreationFailure, Device, GridDimmensions, Kernel, KernelLaunchFailure, OCLFailure, Token, OCL_SHARED_CONTEXT};
+
+// OpenCL 3.0 extension query, missing from cl-sys
+const CL_DEVICE_EXTENSIONS_WITH_VERSION: cl_sys::cl_device_info = 0x1060;
+#[repr(C)]
+struct NameVersion {
+    version: u32,
+    name: [u8; 64]
+}
+// cl_khr_priority_hints and cl_khr_throttle_hints, missing from cl-sys
+const CL_QUEUE_PRIORITY_KHR: cl_queue_properties = 0x1096;
+const CL_QUEUE_THROTTLE_KHR: cl_queue_properties = 0x1097;
+
+#[derive(Debug, Clone, Copy)]
+pub enum QueuePriority {
+    High, Medium, Low
+}
+#[derive(Debug, Clone, Copy)]
+pub enum QueueThrottle {
+    High, Medium, Low
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueOptions {
+    pub out_of_order: bool,
+    pub profiling: bool,
+    pub priority: Option<QueuePriority>,
+    pub throttle: Option<QueueThrottle>
+}
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions {
+            out_of_order: true,
+            profiling: false,
+            priority: None,
+            throttle: None
+        }
+    }
+}
+
+pub struct Queue {
+    handle: cl_command_queue,
+    device: cl_device_id,
+    out_of_order: bool,
+    profiling: bool
+}
+// Queue handles are thread safe
+unsafe impl Send for Queue {}
+unsafe impl Sync for Queue {}
+impl Queue {
+    pub fn is_out_of_order(&self) -> bool { self.out_of_order }
+    pub fn is_profiling(&self) -> bool { self.profiling }
+    pub fn flush(&self) -> Result<(), OCLFailure> {
+        queue_op(self.handle, clFlush)
+    }
+    pub fn finish(&self) -> Result<(), OCLFailure> {
+        queue_op(self.handle, clFinish)
+    }
+}
+impl Drop for Queue {
+    fn drop(&mut self) {
+        let _ = unsafe { clReleaseCommandQueue(self.handle) };
+    }
+}
+
+pub(crate) fn has_extension(device: cl_device_id, name: &str) -> bool { unsafe {
+    let mut len = 0;
+    let ret_code = clGetDeviceInfo(device, CL_DEVICE_EXTENSIONS, 0, null_mut(), &mut len);
+    if ret_code != cl_sys::CL_SUCCESS {
+        return false;
+    }
+    let mut bytes = vec![0u8; len];
+    let ret_code = clGetDeviceInfo(
+        device,
+        CL_DEVICE_EXTENSIONS,
+        len,
+        bytes.as_mut_ptr().cast(),
+        null_mut()
+    );
+    if ret_code != cl_sys::CL_SUCCESS {
+        return false;
+    }
+    bytes.split(|b| *b == b' ' || *b == 0).any(|ext| ext == name.as_bytes())
+} }
+// Needs OpenCL 3.0, older devices report no versions
+pub(crate) fn extension_version(device: cl_device_id, name: &str) -> Option<u32> { unsafe {
+    let mut len = 0;
+    let ret_code = clGetDeviceInfo(device, CL_DEVICE_EXTENSIONS_WITH_VERSION, 0, null_mut(), &mut len);
+    if ret_code != cl_sys::CL_SUCCESS {
+        return None;
+    }
+    let mut versions = Vec::<NameVersion>::new();
+    versions.reserve(len / size_of::<NameVersion>());
+    let ret_code = clGetDeviceInfo(
+        device,
+        CL_DEVICE_EXTENSIONS_WITH_VERSION,
+        len,
+        versions.as_mut_ptr().cast(),
+        null_mut()
+    );
+    if ret_code != cl_sys::CL_SUCCESS {
+        return None;
+    }
+    versions.set_len(len / size_of::<NameVersion>());
+    let found = versions.iter().find(|ext| {
+        let end = ext.name.iter().position(|b| *b == 0).unwrap_or(ext.name.len());
+        &ext.name[..end] == name.as_bytes()
+    });
+    return found.map(|ext| ext.version);
+} }
+pub(crate) const fn make_version(major: u32, minor: u32, patch: u32) -> u32 {
+    (major << 22) | (minor << 12) | patch
+}
+
+impl Device {
+    pub fn create_queue(&self, options: QueueOptions) -> Result<Queue, OCLFailure> { unsafe {
+        let props =
+            if options.out_of_order { CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE } else { 0 } |
+            if options.profiling { CL_QUEUE_PROFILING_ENABLE } else { 0 };
+        let mut hints = Vec::new();
+        if let Some(priority) = options.priority {
+            if has_extension(self.ext.handle, "cl_khr_priority_hints") {
+                let level = match priority {
+                    QueuePriority::High => 1 << 0,
+                    QueuePriority::Medium => 1 << 1,
+                    QueuePriority::Low => 1 << 2,
+                };
+                hints.extend_from_slice(&[CL_QUEUE_PRIORITY_KHR, level]);
+            }
+        }
+        if let Some(throttle) = options.throttle {
+            if has_extension(self.ext.handle, "cl_khr_throttle_hints") {
+                let level = match throttle {
+                    QueueThrottle::High => 1 << 0,
+                    QueueThrottle::Medium => 1 << 1,
+                    QueueThrottle::Low => 1 << 2,
+                };
+                hints.extend_from_slice(&[CL_QUEUE_THROTTLE_KHR, level]);
+            }
+        }
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        // Hints are best effort, a device may still reject them
+        let created = match make_command_queue(ctx, self.ext.handle, props, &hints) {
+            Err(QueueCreationFailure::InvalidProperties) => make_command_queue(ctx, self.ext.handle, props, &[]),
+            created => created
+        };
+        let (handle, actual_props) = match created {
+            Ok(queue) => queue,
+            Err(_) => return Err(OCLFailure::ResourcesExhausted),
+        };
+        let val = Queue {
+            handle: handle,
+            device: self.ext.handle,
+            out_of_order: actual_props & CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE != 0,
+            profiling: actual_props & CL_QUEUE_PROFILING_ENABLE != 0
+        };
+        return Ok(val);
+    } }
+    pub fn launch_kernel_on(
+        &self,
+        queue: &Queue,
+        kernel: Kernel,
+        grid_dimmensions: impl GridDimmensions,
+        dependencies: &[&Token]
+    ) -> Result<Token, KernelLaunchFailure> {
+        if queue.device != self.ext.handle {
+            return Err(KernelLaunchFailure::ForeignQueue);
+        }
+        self.enqueue_kernel(queue.handle, kernel, grid_dimmensions, dependencies)
+    }
+}
+
+#[test]
+fn side_queues() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let in_order = dev.create_queue(QueueOptions {
+        out_of_order: false,
+        ..QueueOptions::default()
+    }).unwrap();
+    assert!(!in_order.is_out_of_order());
+    let urgent = dev.create_queue(QueueOptions {
+        priority: Some(QueuePriority::High),
+        throttle: Some(QueueThrottle::Low),
+        ..QueueOptions::default()
+    }).unwrap();
+
+    let item_count = 65535;
+    let mem = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let text = r#"
+    __kernel void fill(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] = gix;
+    }
+    __kernel void double_up(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let tok1 = dev.launch_kernel_on(&in_order, bundle.instantiate_kernel("fill", (mem,)).unwrap(), (item_count,), &[]).unwrap();
+    let tok2 = dev.launch_kernel_on(&urgent, bundle.instantiate_kernel("double_up", (mem,)).unwrap(), (item_count,), &[&tok1]).unwrap();
+    urgent.flush().unwrap();
+    tok2.await_completion().unwrap();
+    in_order.finish().unwrap();
+
+    for (ix, i) in mem.as_items().iter().enumerate() {
+        assert!(*i == ix as u32 * 2);
+    }
+    if let Some(other) = devs.get(1) {
+        let kern = bundle.instantiate_kernel("fill", (mem,)).unwrap();
+        match other.launch_kernel_on(&in_order, kern, (item_count,), &[]) {
+            Err(KernelLaunchFailure::ForeignQueue) => (),
+            _ => panic!("Kernel launched on a queue of another device")
+        }
+    }
+    dev.deallocate_memory(mem);
+}
@@ -0,0 +1,216 @@
+use core::{any::TypeId, marker::PhantomData, mem::{align_of_val, size_of_val, transmute}, ptr::drop_in_place};
+use std::{panic::{catch_unwind, resume_unwind, AssertUnwindSafe}, sync::{Arc, Mutex}};
+
+use crate::{va_args_emu::{self, ErasedRef, KernelArguments, SomeSvmPointer}, BufferTracker, CodeBundle, Device, ExecutionError, ExecutionState, GridDimmensions, Kernel, KernelCreationFailure, KernelLaunchFailure, Token};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ScopeFailure {
+    ResourcesExhausted,
+    Unsupported
+}
+
+pub struct Scope<'scope, 'env: 'scope> {
+    device: &'env Device,
+    // leased by kernels bound to lent memory and by pending callbacks
+    tracker: Arc<BufferTracker>,
+    tokens: Mutex<Vec<Token>>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>
+}
+
+pub struct HostSlice<'scope, T> {
+    ptr: *mut T,
+    len: usize,
+    tracker: Arc<BufferTracker>,
+    _borrow: PhantomData<&'scope mut [T]>
+}
+unsafe impl<T: Send> Send for HostSlice<'_, T> {}
+unsafe impl<T: Sync> Sync for HostSlice<'_, T> {}
+impl<T> HostSlice<'_, T> {
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+}
+// A kernel that may borrow host memory of the scope, so it can not outlive it
+pub struct ScopedKernel<'scope> {
+    kernel: Kernel,
+    _scope: PhantomData<&'scope mut &'scope ()>
+}
+impl ScopedKernel<'_> {
+    pub fn rebind(&mut self, args: impl KernelArguments) -> Result<(), KernelCreationFailure> {
+        self.kernel.rebind(args)
+    }
+}
+impl From<Kernel> for ScopedKernel<'_> {
+    fn from(kernel: Kernel) -> Self {
+        ScopedKernel {
+            kernel: kernel,
+            _scope: PhantomData
+        }
+    }
+}
+
+impl<T> va_args_emu::KernelArgument for &HostSlice<'_, T> {
+    fn as_opaque(&self) -> ErasedRef {
+        ErasedRef {
+            data_ptr: self.ptr.cast(),
+            size: size_of_val(*self),
+            alignment: align_of_val(*self),
+            type_id: TypeId::of::<SomeSvmPointer>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            // the scope waits for every kernel holding this lease
+            tracker: Some(self.tracker.clone())
+        }
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub fn device(&self) -> &'env Device { self.device }
+    pub fn instantiate_kernel(
+        &self,
+        bundle: &CodeBundle,
+        name: &str,
+        args: impl KernelArguments
+    ) -> Result<ScopedKernel<'scope>, KernelCreationFailure> {
+        let kernel = bundle.instantiate_kernel(name, args)?;
+        return Ok(ScopedKernel::from(kernel));
+    }
+    pub fn launch(
+        &self,
+        kernel: impl Into<ScopedKernel<'scope>>,
+        grid_dimmensions: impl GridDimmensions,
+        dependencies: &[&Token]
+    ) -> Result<Token, KernelLaunchFailure> {
+        let kernel = kernel.into().kernel;
+        // plain kernels may still carry slices lent by another scope
+        if kernel.lent_trackers().any(|t| !Arc::ptr_eq(t, &self.tracker)) {
+            return Err(KernelLaunchFailure::OutOfScope);
+        }
+        let queue = self.device.ext.command_queue;
+        let dims = grid_dimmensions.as_components();
+        let tok = self.device.enqueue_kernel_lent(queue, &kernel, grid_dimmensions.dims(), dims, dependencies)?;
+        self.tokens.lock().unwrap().push(tok.retained());
+        return Ok(tok);
+    }
+    pub fn lend<T>(&self, data: &'scope mut [T]) -> Result<HostSlice<'scope, T>, ScopeFailure> {
+        if !self.device.ext.props.shared_mem_caps.fine_grain_system {
+            return Err(ScopeFailure::Unsupported);
+        }
+        let val = HostSlice {
+            ptr: data.as_mut_ptr(),
+            len: data.len(),
+            tracker: self.tracker.clone(),
+            _borrow: PhantomData
+        };
+        return Ok(val);
+    }
+    pub fn on_complete<F>(&self, token: &Token, action: F) -> Result<(), ScopeFailure>
+        where F: FnOnce(Result<ExecutionState, ExecutionError>) + Send + 'scope
+    {
+        let action: Box<dyn FnOnce(Result<ExecutionState, ExecutionError>) + Send + 'scope> = Box::new(action);
+        // sound since the scope outlives the lease released below
+        let action: Box<dyn FnOnce(Result<ExecutionState, ExecutionError>) + Send + 'static> = unsafe { transmute(action) };
+        let tracker = self.tracker.clone();
+        tracker.acquire();
+        let outcome = token.attach_completion_callback(move |state| {
+            action(state);
+            tracker.release();
+        });
+        if outcome.is_err() {
+            self.tracker.release();
+            return Err(ScopeFailure::ResourcesExhausted);
+        }
+        return Ok(());
+    }
+}
+
+impl Device {
+    // Returns only once every launch and callback of the scope is done,
+    // even if `f` panics
+    pub fn scope<'env, F, R>(&'env self, f: F) -> R
+        where F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R
+    {
+        let scope = Scope {
+            device: self,
+            tracker: Arc::new(BufferTracker::new_lent()),
+            tokens: Mutex::new(Vec::new()),
+            scope: PhantomData,
+            env: PhantomData
+        };
+        let outcome = catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // a panicking thread may have poisoned the lock, the list is still whole
+        let tokens = match scope.tokens.lock() {
+            Ok(mut tokens) => core::mem::take(&mut *tokens),
+            Err(poisoned) => core::mem::take(&mut *poisoned.into_inner()),
+        };
+        for tok in &tokens {
+            let _ = tok.await_completion();
+        }
+        scope.tracker.retire();
+        match outcome {
+            Ok(val) => return val,
+            Err(payload) => resume_unwind(payload),
+        }
+    }
+}
+
+#[test]
+fn scoped_borrows() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let item_count = 4096;
+    let mem = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let mut host = vec![1u32; item_count];
+    let text = r#"
+    __kernel void fill(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] = gix;
+    }
+    __kernel void double_up(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+
+    let completed = core::sync::atomic::AtomicU32::new(0);
+    let failed = core::sync::atomic::AtomicBool::new(false);
+    let lent = dev.scope(|s| {
+        let tok = s.launch(bundle.instantiate_kernel("fill", (mem,)).unwrap(), (item_count,), &[]).unwrap();
+        // a panic here would be lost on the callback thread
+        s.on_complete(&tok, |state| {
+            if !matches!(state, Ok(ExecutionState::Complete)) {
+                failed.store(true, core::sync::atomic::Ordering::Relaxed);
+            }
+            completed.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        }).unwrap();
+        // never awaited here, the scope does that
+        s.launch(bundle.instantiate_kernel("double_up", (mem,)).unwrap(), (item_count,), &[&tok]).unwrap();
+        match s.lend(&mut host) {
+            Ok(slice) => {
+                assert!(slice.len() == item_count);
+                let kern = bundle.instantiate_kernel("double_up", (&slice,)).unwrap();
+                match dev.launch_kernel(kern, (item_count,), &[]) {
+                    Err(KernelLaunchFailure::OutOfScope) => (),
+                    _ => panic!("Lent slice launched outside of its scope")
+                }
+                let kern = s.instantiate_kernel(&bundle, "double_up", (&slice,)).unwrap();
+                s.launch(kern, (item_count,), &[]).unwrap();
+                true
+            },
+            Err(ScopeFailure::Unsupported) => false,
+            Err(err) => panic!("{:?}", err),
+        }
+    });
+
+    assert!(completed.load(core::sync::atomic::Ordering::Relaxed) == 1);
+    assert!(!failed.load(core::sync::atomic::Ordering::Relaxed));
+    for (ix, i) in mem.as_items().iter().enumerate() {
+        assert!(*i == ix as u32 * 2);
+    }
+    if lent {
+        assert!(host.iter().all(|i| *i == 2));
+    }
+    dev.deallocate_memory(mem);
+}
@@ -0,0 +1,335 @@
+use core::{any::TypeId, marker::PhantomData, mem::{align_of_val, size_of, size_of_val, transmute}, ops::Range, ptr::{addr_of, drop_in_place, null, null_mut}};
+use std::sync::Arc;
+
+use cl_sys::{c_void, clCreateBuffer, clCreateSubBuffer, clEnqueueReadBuffer, clEnqueueWriteBuffer, clReleaseMemObject, cl_buffer_region, cl_mem, CL_BUFFER_CREATE_TYPE_REGION, CL_MEM_READ_WRITE, CL_SUCCESS, CL_TRUE};
+
+use crate::{trace, va_args_emu::{self, ErasedRef}, BufferTracker, Device, MemoryRef, OCLFailure, SomeMemoryRef, SvmVec, OCL_SHARED_CONTEXT};
+
+fn check_range(range: &Range<usize>, len: usize) {
+    assert!(
+        range.start <= range.end && range.end <= len,
+        "Range {:?} is out of buffer of length {}", range, len
+    );
+}
+
+impl<T> MemoryRef<T> {
+    pub fn slice(&self, range: Range<usize>) -> MemoryRef<T> {
+        check_range(&range, self.count);
+        MemoryRef {
+            ptr: unsafe { self.ptr.cast::<T>().add(range.start).cast() },
+            count: range.end - range.start,
+            generation: self.generation,
+            atomics: self.atomics,
+            _phantom: PhantomData
+        }
+    }
+    pub fn split_at(&self, mid: usize) -> (MemoryRef<T>, MemoryRef<T>) {
+        (self.slice(0 .. mid), self.slice(mid .. self.count))
+    }
+}
+
+#[repr(C)]
+pub struct SvmSlice<'a, T> {
+    pub(crate) ptr: *mut c_void,
+    count: usize,
+    tracker: Arc<BufferTracker>,
+    _phantom: PhantomData<&'a [T]>
+}
+unsafe impl<'a, T: Sync> Send for SvmSlice<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for SvmSlice<'a, T> {}
+impl<'a, T> Clone for SvmSlice<'a, T> {
+    fn clone(&self) -> Self {
+        SvmSlice {
+            ptr: self.ptr,
+            count: self.count,
+            tracker: self.tracker.clone(),
+            _phantom: PhantomData
+        }
+    }
+}
+impl<'a, T> SvmSlice<'a, T> {
+    pub fn len(&self) -> usize { self.count }
+    pub fn as_items(&self) -> &'a [T] {
+        unsafe { core::slice::from_raw_parts(self.ptr.cast(), self.count) }
+    }
+    pub fn slice(&self, range: Range<usize>) -> SvmSlice<'a, T> {
+        check_range(&range, self.count);
+        SvmSlice {
+            ptr: unsafe { self.ptr.cast::<T>().add(range.start).cast() },
+            count: range.end - range.start,
+            tracker: self.tracker.clone(),
+            _phantom: PhantomData
+        }
+    }
+    pub fn split_at(&self, mid: usize) -> (SvmSlice<'a, T>, SvmSlice<'a, T>) {
+        (self.slice(0 .. mid), self.slice(mid .. self.count))
+    }
+}
+impl<'a, T> va_args_emu::KernelArgument for SvmSlice<'a, T> {
+    fn as_opaque(&self) -> ErasedRef {
+        ErasedRef {
+            data_ptr: addr_of!(*self).cast(),
+            size: size_of_val(self),
+            alignment: align_of_val(self),
+            type_id: TypeId::of::<SomeMemoryRef>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: Some(self.tracker.clone())
+        }
+    }
+}
+impl<T> SvmVec<T> {
+    pub fn as_svm_slice(&self) -> SvmSlice<'_, T> {
+        SvmSlice {
+            ptr: self.as_ptr().cast_mut().cast(),
+            count: self.len(),
+            tracker: self.tracker.clone(),
+            _phantom: PhantomData
+        }
+    }
+    pub fn svm_slice(&self, range: Range<usize>) -> SvmSlice<'_, T> {
+        self.as_svm_slice().slice(range)
+    }
+    pub fn svm_split_at(&self, mid: usize) -> (SvmSlice<'_, T>, SvmSlice<'_, T>) {
+        self.as_svm_slice().split_at(mid)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SubBufferFailure {
+    ResourcesExhausted,
+    MisalignedOffset,
+    NestedSubBuffer
+}
+pub(crate) struct SomeMemObject {}
+
+pub struct Buffer<T> {
+    handle: cl_mem,
+    count: usize,
+    is_sub_buffer: bool,
+    _phantom: PhantomData<T>
+}
+unsafe impl<T: Send> Send for Buffer<T> {}
+unsafe impl<T: Send> Sync for Buffer<T> {}
+impl<T> Buffer<T> {
+    pub fn len(&self) -> usize { self.count }
+    pub fn is_sub_buffer(&self) -> bool { self.is_sub_buffer }
+}
+impl<T> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        let _ = unsafe { clReleaseMemObject(self.handle) };
+    }
+}
+impl<T> va_args_emu::KernelArgument for &Buffer<T> {
+    fn as_opaque(&self) -> ErasedRef {
+        ErasedRef {
+            data_ptr: addr_of!(self.handle).cast(),
+            size: size_of::<cl_mem>(),
+            alignment: align_of_val(&self.handle),
+            type_id: TypeId::of::<SomeMemObject>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: None
+        }
+    }
+}
+impl Device {
+    pub fn allocate_device_buffer<T>(&self, count: usize) -> Result<Buffer<T>, OCLFailure> { unsafe {
+        assert!(count > 0, "Item count cannot be zero");
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        let mut ret_code = CL_SUCCESS;
+        let handle = clCreateBuffer(
+            ctx,
+            CL_MEM_READ_WRITE,
+            size_of::<T>() * count,
+            null_mut(),
+            &mut ret_code
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY |
+            cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE |
+            cl_sys::CL_INVALID_BUFFER_SIZE => {
+                return Err(OCLFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_CONTEXT |
+            cl_sys::CL_INVALID_VALUE |
+            _ => unreachable!()
+        }
+        let val = Buffer {
+            handle: handle,
+            count: count,
+            is_sub_buffer: false,
+            _phantom: PhantomData
+        };
+        return Ok(val);
+    } }
+    pub fn create_sub_buffer<T>(
+        &self,
+        parent: &Buffer<T>,
+        range: Range<usize>
+    ) -> Result<Buffer<T>, SubBufferFailure> { unsafe {
+        check_range(&range, parent.count);
+        assert!(range.start != range.end, "Sub-buffer cannot be empty");
+        if parent.is_sub_buffer {
+            return Err(SubBufferFailure::NestedSubBuffer);
+        }
+        let origin = range.start * size_of::<T>();
+        let align = self.ext.props.mem_base_addr_align as usize;
+        if align != 0 && origin % align != 0 {
+            return Err(SubBufferFailure::MisalignedOffset);
+        }
+        let region = cl_buffer_region {
+            origin: origin,
+            size: (range.end - range.start) * size_of::<T>()
+        };
+        let mut ret_code = CL_SUCCESS;
+        let handle = clCreateSubBuffer(
+            parent.handle,
+            CL_MEM_READ_WRITE,
+            CL_BUFFER_CREATE_TYPE_REGION,
+            addr_of!(region).cast(),
+            &mut ret_code
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY |
+            cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE => {
+                return Err(SubBufferFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_MISALIGNED_SUB_BUFFER_OFFSET => {
+                return Err(SubBufferFailure::MisalignedOffset)
+            },
+            cl_sys::CL_INVALID_MEM_OBJECT |
+            cl_sys::CL_INVALID_VALUE |
+            cl_sys::CL_INVALID_BUFFER_SIZE |
+            _ => unreachable!()
+        }
+        let val = Buffer {
+            handle: handle,
+            count: range.end - range.start,
+            is_sub_buffer: true,
+            _phantom: PhantomData
+        };
+        return Ok(val);
+    } }
+    pub fn write_buffer<T: Copy>(&self, buffer: &Buffer<T>, data: &[T]) -> Result<(), SubBufferFailure> { unsafe {
+        assert!(data.len() == buffer.count, "Data length differs from buffer length");
+        let mut event = null_mut();
+        let ret_code = clEnqueueWriteBuffer(
+            self.ext.command_queue,
+            buffer.handle,
+            CL_TRUE,
+            0,
+            size_of_val(data),
+            data.as_ptr().cast(),
+            0,
+            null(),
+            trace::event_slot(&mut event)
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY |
+            cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE => {
+                return Err(SubBufferFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_MISALIGNED_SUB_BUFFER_OFFSET => {
+                return Err(SubBufferFailure::MisalignedOffset)
+            },
+            _ => unreachable!()
+        }
+        trace::record_copy(self.ext.command_queue, event, "write_buffer");
+        return Ok(());
+    } }
+    pub fn read_buffer<T: Copy>(&self, buffer: &Buffer<T>, data: &mut [T]) -> Result<(), SubBufferFailure> { unsafe {
+        assert!(data.len() == buffer.count, "Data length differs from buffer length");
+        let mut event = null_mut();
+        let ret_code = clEnqueueReadBuffer(
+            self.ext.command_queue,
+            buffer.handle,
+            CL_TRUE,
+            0,
+            size_of_val(data),
+            data.as_mut_ptr().cast(),
+            0,
+            null(),
+            trace::event_slot(&mut event)
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY |
+            cl_sys::CL_MEM_OBJECT_ALLOCATION_FAILURE => {
+                return Err(SubBufferFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_MISALIGNED_SUB_BUFFER_OFFSET => {
+                return Err(SubBufferFailure::MisalignedOffset)
+            },
+            _ => unreachable!()
+        }
+        trace::record_copy(self.ext.command_queue, event, "read_buffer");
+        return Ok(());
+    } }
+}
+
+#[test]
+fn windows() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let text = r#"
+    __kernel void lol(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+
+    let vec = (0 .. 1024u32).collect::<SvmVec<_>>();
+    let (_, tail) = vec.svm_split_at(512);
+    let kern = bundle.instantiate_kernel("lol", (tail,)).unwrap();
+    let tok = dev.launch_kernel(kern, (512,), &[]).unwrap();
+    tok.await_completion().unwrap();
+    while vec.has_pending_launches() {}
+    for (ix, i) in vec.iter().enumerate() {
+        let k = if ix < 512 { ix } else { ix * 2 };
+        assert!(*i == k as u32);
+    }
+
+    let mem = dev.allocate_buffer::<u32>(1024).unwrap();
+    let stale = bundle.instantiate_kernel("lol", (mem.slice(512 .. 1024),)).unwrap();
+    let freed = std::panic::catch_unwind(|| dev.deallocate_memory(mem.slice(512 .. 1024)));
+    assert!(freed.is_err());
+    dev.deallocate_memory(mem);
+    match dev.launch_kernel(stale, (512,), &[]) {
+        Err(crate::KernelLaunchFailure::ArgumentReleased) => (),
+        _ => panic!("Kernel launched over a freed parent")
+    }
+
+    let align = dev.get_properties().mem_base_addr_align as usize / size_of::<u32>();
+    let buffer = dev.allocate_device_buffer::<u32>(align * 2).unwrap();
+    let data = (0 .. align as u32 * 2).collect::<Vec<_>>();
+    dev.write_buffer(&buffer, &data).unwrap();
+    if align > 1 {
+        match dev.create_sub_buffer(&buffer, 1 .. align) {
+            Err(SubBufferFailure::MisalignedOffset) => (),
+            _ => panic!("Misaligned sub-buffer was created")
+        }
+    }
+    let window = dev.create_sub_buffer(&buffer, align .. align * 2).unwrap();
+    match dev.create_sub_buffer(&window, 0 .. align) {
+        Err(SubBufferFailure::NestedSubBuffer) => (),
+        _ => panic!("Sub-buffer of a sub-buffer was created")
+    }
+    drop(buffer);
+    let kern = bundle.instantiate_kernel("lol", (&window,)).unwrap();
+    let tok = dev.launch_kernel(kern, (align,), &[]).unwrap();
+    tok.await_completion().unwrap();
+    let mut out = vec![0u32; align];
+    dev.read_buffer(&window, &mut out).unwrap();
+    for (ix, i) in out.iter().enumerate() {
+        assert!(*i == (align + ix) as u32 * 2);
+    }
+}
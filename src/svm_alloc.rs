@@ -0,0 +1,204 @@
+use core::{alloc::{AllocError, Allocator, Layout}, any::TypeId, mem::{align_of_val, size_of_val, transmute}, ptr::{copy_nonoverlapping, drop_in_place, null, null_mut, NonNull}};
+use std::sync::Arc;
+
+use cl_sys::{c_void, cl_svm_mem_flags, clSVMAlloc, clSVMFree, CL_MEM_READ_WRITE, CL_MEM_SVM_ATOMICS, CL_MEM_SVM_FINE_GRAIN_BUFFER};
+
+use crate::{allocation_tracker, register_allocation, retire_allocation, va_args_emu::{self, ErasedRef, SomeSvmPointer}, BufferTracker, Device, SvmRegion, OCL_SHARED_CONTEXT};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SvmAllocator {
+    alloc_props: cl_svm_mem_flags,
+    min_align: usize,
+    generation: u32
+}
+unsafe impl Allocator for SvmAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> { unsafe {
+        let align = layout.align().max(self.min_align);
+        if layout.size() == 0 {
+            let dangling = NonNull::new_unchecked(align as *mut u8);
+            return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+        }
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        let ptr = clSVMAlloc(
+            ctx,
+            self.alloc_props,
+            layout.size(),
+            align as _
+        );
+        if ptr == null_mut() {
+            return Err(AllocError);
+        }
+        register_allocation(ptr, layout.size());
+        let ptr = NonNull::new_unchecked(ptr.cast::<u8>());
+        return Ok(NonNull::slice_from_raw_parts(ptr, layout.size()));
+    } }
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 { return }
+        retire_allocation(ptr.as_ptr().cast());
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        clSVMFree(ctx, ptr.as_ptr().cast());
+    }
+    unsafe fn grow(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.reallocate(ptr, old_layout, new_layout)
+    }
+    unsafe fn grow_zeroed(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = self.reallocate(ptr, old_layout, new_layout)?;
+        new_ptr.cast::<u8>().add(old_layout.size()).write_bytes(0, new_layout.size() - old_layout.size());
+        return Ok(new_ptr);
+    }
+    unsafe fn shrink(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.reallocate(ptr, old_layout, new_layout)
+    }
+}
+impl SvmAllocator {
+    // Same as `SvmVec`, the old block is not copied while a launch may
+    // still write it, those holding it refuse the move instead
+    unsafe fn reallocate(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = self.allocate(new_layout)?;
+        if old_layout.size() != 0 {
+            if let Some(tracker) = allocation_tracker(ptr.as_ptr().cast()) {
+                if !tracker.try_retire() {
+                    self.deallocate(new_ptr.cast(), new_layout);
+                    return Err(AllocError);
+                }
+            }
+        }
+        let count = old_layout.size().min(new_layout.size());
+        copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast::<u8>().as_ptr(), count);
+        self.deallocate(ptr, old_layout);
+        return Ok(new_ptr);
+    }
+}
+impl Device {
+    pub fn svm_allocator(&self) -> SvmAllocator {
+        let caps = self.ext.props.shared_mem_caps;
+        assert!(caps.fine_grain_buffer, "No support of fine-grain buffers!");
+        let alloc_props =
+            CL_MEM_READ_WRITE |
+            CL_MEM_SVM_FINE_GRAIN_BUFFER |
+            if caps.svm_atomics { CL_MEM_SVM_ATOMICS } else { 0 };
+        SvmAllocator {
+            alloc_props: alloc_props,
+            min_align: (caps.preffered_platform_atomic_alignment as usize).max(1),
+            generation: unsafe { OCL_SHARED_CONTEXT.generation }
+        }
+    }
+}
+impl<T> SvmRegion for Vec<T, SvmAllocator> {
+    fn svm_base_ptr(&self) -> *mut c_void {
+        if self.capacity() == 0 { null_mut() } else { self.as_ptr().cast_mut().cast() }
+    }
+    fn context_generation(&self) -> u32 { self.allocator().generation }
+    fn tracker(&self) -> Option<Arc<BufferTracker>> { allocation_tracker(self.svm_base_ptr()) }
+}
+impl<T> SvmRegion for Box<T, SvmAllocator> {
+    fn svm_base_ptr(&self) -> *mut c_void {
+        (&**self as *const T).cast_mut().cast()
+    }
+    fn context_generation(&self) -> u32 { Box::allocator(self).generation }
+    fn tracker(&self) -> Option<Arc<BufferTracker>> { allocation_tracker(self.svm_base_ptr()) }
+}
+// Empty ones bind as null, which binding rejects
+impl<T> va_args_emu::KernelArgument for &Vec<T, SvmAllocator> {
+    fn as_opaque(&self) -> ErasedRef {
+        let ptr = if self.is_empty() { null_mut() } else { self.svm_base_ptr() };
+        ErasedRef {
+            data_ptr: ptr.cast_const().cast(),
+            size: size_of_val(*self),
+            alignment: align_of_val(*self),
+            type_id: TypeId::of::<SomeSvmPointer>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: allocation_tracker(ptr)
+        }
+    }
+}
+impl<T> va_args_emu::KernelArgument for &Box<T, SvmAllocator> {
+    fn as_opaque(&self) -> ErasedRef {
+        let ptr: *const c_void = if size_of_val(&***self) == 0 { null() } else { (&***self as *const T).cast() };
+        ErasedRef {
+            data_ptr: ptr.cast(),
+            size: size_of_val(*self),
+            alignment: align_of_val(*self),
+            type_id: TypeId::of::<SomeSvmPointer>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: allocation_tracker(ptr)
+        }
+    }
+}
+
+#[test]
+fn svm_collections() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let alloc = dev.svm_allocator();
+    let mut items = Vec::new_in(alloc);
+    for ix in 0 .. 4096u32 {
+        items.push(ix);
+    }
+    let header = Box::new_in(items.len() as u32, alloc);
+
+    let text = r#"
+    __kernel void lol(__global uint* items, __global uint* count) {
+        uint gix = get_global_id(0);
+        if (gix < *count) items[gix] *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let empty = Vec::<u32, _>::new_in(alloc);
+    match bundle.instantiate_kernel("lol", (&empty, &header,)) {
+        Err(crate::KernelCreationFailure::InvalidArgument(0)) => (),
+        _ => panic!("Empty vector was bound")
+    }
+    let gate = crate::UserToken::new().unwrap();
+    let kern = bundle.instantiate_kernel("lol", (&items, &header,)).unwrap();
+    let tok = dev.launch_kernel(kern, (items.len(),), &[&gate]).unwrap();
+    assert!(items.try_reserve(items.capacity()).is_err());
+    gate.complete().unwrap();
+    tok.await_completion().unwrap();
+
+    let mut ix = 0;
+    for i in items.iter() {
+        assert!(*i == ix * 2);
+        ix += 1;
+    }
+}
+
+#[test]
+fn indirect_access() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let alloc = dev.svm_allocator();
+    let mut nodes = Vec::new_in(alloc);
+    for ix in 0 .. 64u32 {
+        nodes.push(Box::new_in(ix, alloc));
+    }
+    let mut table = Vec::new_in(alloc);
+    for node in nodes.iter() {
+        table.push(&**node as *const u32 as u64);
+    }
+
+    let text = r#"
+    __kernel void lol(__global ulong* table) {
+        uint gix = get_global_id(0);
+        __global uint* node = (__global uint*)table[gix];
+        *node *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let mut kern = bundle.instantiate_kernel("lol", (&table,)).unwrap();
+    kern.declare_indirect_access(&[]).unwrap();
+    let regions = nodes.iter().map(|i| i as &dyn SvmRegion).collect::<Vec<_>>();
+    kern.declare_indirect_access(&regions).unwrap();
+    let tok = dev.launch_kernel(kern, (table.len(),), &[]).unwrap();
+    tok.await_completion().unwrap();
+
+    let mut ix = 0;
+    for node in nodes.iter() {
+        assert!(**node == ix * 2);
+        ix += 1;
+    }
+}
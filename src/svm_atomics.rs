@@ -0,0 +1,70 @@
+use core::sync::atomic::{AtomicI32, AtomicI64, AtomicU32, AtomicU64};
+
+use crate::MemoryRef;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+pub trait SvmAtomicItem: sealed::Sealed {
+    type Atomic;
+}
+macro_rules! atomic_items {
+    ($($item:ty => $atomic:ty),+) => {
+      $(
+        impl sealed::Sealed for $item {}
+        impl SvmAtomicItem for $item {
+            type Atomic = $atomic;
+        }
+      )+
+    };
+}
+atomic_items!(
+    u32 => AtomicU32,
+    i32 => AtomicI32,
+    u64 => AtomicU64,
+    i64 => AtomicI64
+);
+
+impl<T: SvmAtomicItem> MemoryRef<T> {
+    // Host orderings pair with device atomics of memory_scope_all_svm_devices,
+    // other device accesses are visible once the launch token completes
+    pub fn as_atomic_items(&self) -> &[T::Atomic] {
+        assert!(self.atomics, "Buffer was allocated without SVM atomics!");
+        unsafe { core::slice::from_raw_parts(self.ptr.cast(), self.count) }
+    }
+}
+
+#[test]
+fn shared_counter() {
+    use core::sync::atomic::Ordering;
+
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+    if !dev.get_properties().shared_mem_caps.svm_atomics { return }
+
+    let counter = dev.allocate_buffer::<u32>(1).unwrap();
+    counter.as_atomic_items()[0].store(0, Ordering::Release);
+
+    let text = r#"
+    __kernel void lol(volatile __global atomic_uint* counter) {
+        atomic_fetch_add_explicit(
+            counter, 1, memory_order_acq_rel, memory_scope_all_svm_devices);
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let item_count = 4096;
+    let kern = bundle.instantiate_kernel("lol", (counter,)).unwrap();
+    let tok = dev.launch_kernel(kern, (item_count,), &[]).unwrap();
+
+    let mut last = 0;
+    while last != item_count as u32 {
+        let now = counter.as_atomic_items()[0].load(Ordering::Acquire);
+        assert!(now >= last);
+        last = now;
+    }
+    tok.await_completion().unwrap();
+
+    dev.deallocate_memory(counter);
+}
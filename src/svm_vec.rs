@@ -0,0 +1,243 @@
+use core::{any::TypeId, marker::PhantomData, mem::{align_of, align_of_val, needs_drop, size_of, size_of_val, transmute}, ops::{Deref, DerefMut}, ptr::{addr_of, copy_nonoverlapping, drop_in_place, null_mut, NonNull}};
+use std::sync::Arc;
+
+use cl_sys::{c_void, clSVMAlloc, clSVMFree, CL_MEM_READ_WRITE, CL_MEM_SVM_FINE_GRAIN_BUFFER};
+
+use crate::{va_args_emu::{self, ErasedRef}, BufferTracker, SomeMemoryRef, SvmRegion, OCL_SHARED_CONTEXT};
+
+#[derive(Debug, Clone, Copy)]
+pub enum SvmVecFailure {
+    ResourcesExhausted,
+    ReferencedByPendingLaunch
+}
+
+#[repr(C)]
+pub struct SvmVec<T> {
+    pub(crate) ptr: *mut c_void,
+    len: usize,
+    capacity: usize,
+    generation: u32,
+    pub(crate) tracker: Arc<BufferTracker>,
+    _phantom: PhantomData<T>
+}
+unsafe impl<T: Send> Send for SvmVec<T> {}
+unsafe impl<T: Sync> Sync for SvmVec<T> {}
+impl<T> SvmVec<T> {
+    pub fn new() -> Self {
+        assert!(size_of::<T>() != 0, "Zero sized items cannot be placed in SVM");
+        SvmVec {
+            ptr: null_mut(),
+            len: 0,
+            capacity: 0,
+            generation: 0,
+            tracker: Arc::new(BufferTracker::new()),
+            _phantom: PhantomData
+        }
+    }
+    pub fn with_capacity(capacity: usize) -> Result<Self, SvmVecFailure> {
+        let mut vec = Self::new();
+        vec.try_reserve(capacity)?;
+        return Ok(vec);
+    }
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+    pub fn capacity(&self) -> usize { self.capacity }
+    pub fn as_ptr(&self) -> *const T {
+        if self.ptr.is_null() { NonNull::dangling().as_ptr() } else { self.ptr.cast() }
+    }
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        if self.ptr.is_null() { NonNull::dangling().as_ptr() } else { self.ptr.cast() }
+    }
+    pub fn has_pending_launches(&self) -> bool {
+        self.tracker.has_pending_launches()
+    }
+    pub fn set_dependency_tracking(&self, enabled: bool) {
+        self.tracker.set_tracking(enabled)
+    }
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), SvmVecFailure> { unsafe {
+        let required = match self.len.checked_add(additional) {
+            Some(required) => required,
+            None => return Err(SvmVecFailure::ResourcesExhausted),
+        };
+        if required <= self.capacity { return Ok(()) }
+        let new_capacity = required.max(self.capacity * 2).max(4);
+        let size = match new_capacity.checked_mul(size_of::<T>()) {
+            Some(size) => size,
+            None => return Err(SvmVecFailure::ResourcesExhausted),
+        };
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        let new_ptr = clSVMAlloc(
+            ctx,
+            CL_MEM_READ_WRITE | CL_MEM_SVM_FINE_GRAIN_BUFFER,
+            size,
+            align_of::<T>() as _
+        );
+        if new_ptr == null_mut() {
+            return Err(SvmVecFailure::ResourcesExhausted);
+        }
+        if !self.tracker.try_retire() {
+            clSVMFree(ctx, new_ptr);
+            return Err(SvmVecFailure::ReferencedByPendingLaunch);
+        }
+        if !self.ptr.is_null() {
+            copy_nonoverlapping(self.ptr.cast::<T>(), new_ptr.cast::<T>(), self.len);
+            clSVMFree(ctx, self.ptr);
+        }
+        self.ptr = new_ptr;
+        self.capacity = new_capacity;
+        self.generation = OCL_SHARED_CONTEXT.generation;
+        return Ok(());
+    } }
+    pub fn reserve(&mut self, additional: usize) {
+        match self.try_reserve(additional) {
+            Ok(()) => (),
+            Err(SvmVecFailure::ResourcesExhausted) => panic!("Failed to allocate SVM memory!"),
+            Err(SvmVecFailure::ReferencedByPendingLaunch) => {
+                panic!("Attempt to reallocate a vector referenced by a pending launch!")
+            },
+        }
+    }
+    pub fn push(&mut self, item: T) { unsafe {
+        if self.len == self.capacity {
+            self.reserve(1);
+        }
+        self.ptr.cast::<T>().add(self.len).write(item);
+        self.len += 1;
+    } }
+    pub fn pop(&mut self) -> Option<T> { unsafe {
+        if self.len == 0 { return None }
+        self.len -= 1;
+        return Some(self.ptr.cast::<T>().add(self.len).read());
+    } }
+    pub fn truncate(&mut self, len: usize) { unsafe {
+        if len >= self.len { return }
+        let tail = core::slice::from_raw_parts_mut(self.ptr.cast::<T>().add(len), self.len - len);
+        self.len = len;
+        drop_in_place(tail);
+    } }
+    pub fn clear(&mut self) {
+        self.truncate(0)
+    }
+    pub fn resize(&mut self, new_len: usize, value: T) where T: Clone {
+        if new_len <= self.len {
+            self.truncate(new_len);
+            return;
+        }
+        self.reserve(new_len - self.len);
+        while self.len < new_len {
+            self.push(value.clone());
+        }
+    }
+    pub fn extend_from_slice(&mut self, items: &[T]) where T: Clone {
+        self.reserve(items.len());
+        for item in items {
+            self.push(item.clone());
+        }
+    }
+}
+impl<T> Deref for SvmVec<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        self.tracker.await_host_access(false);
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+}
+impl<T> DerefMut for SvmVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.tracker.await_host_access(true);
+        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
+    }
+}
+impl<T> Extend<T> for SvmVec<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        let (lower, _) = iter.size_hint();
+        self.reserve(lower);
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+impl<T> FromIterator<T> for SvmVec<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut vec = SvmVec::new();
+        vec.extend(iter);
+        return vec;
+    }
+}
+impl<T> Drop for SvmVec<T> {
+    fn drop(&mut self) { unsafe {
+        if self.ptr.is_null() { return }
+        self.tracker.retire();
+        if needs_drop::<T>() {
+            drop_in_place(&mut **self as *mut [T]);
+        }
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        clSVMFree(ctx, self.ptr);
+    } }
+}
+impl<T> SvmRegion for SvmVec<T> {
+    fn svm_base_ptr(&self) -> *mut c_void { self.ptr }
+    fn context_generation(&self) -> u32 { self.generation }
+    fn tracker(&self) -> Option<Arc<BufferTracker>> { Some(self.tracker.clone()) }
+}
+impl<T> va_args_emu::KernelArgument for &SvmVec<T> {
+    fn as_opaque(&self) -> ErasedRef {
+        ErasedRef {
+            data_ptr: addr_of!(**self).cast(),
+            size: size_of_val(*self),
+            alignment: align_of_val(*self),
+            type_id: TypeId::of::<SomeMemoryRef>(),
+            dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+            tracker: Some(self.tracker.clone())
+        }
+    }
+}
+
+#[test]
+fn svm_vec_growth() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let mut vec = SvmVec::<u32>::new();
+    for ix in 0 .. 1000 {
+        vec.push(ix);
+    }
+    vec.extend(1000 .. 2000);
+    assert!(vec.len() == 2000);
+
+    let text = r#"
+    __kernel void lol(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let gate = crate::UserToken::new().unwrap();
+    let kern = bundle.instantiate_kernel("lol", (&vec,)).unwrap();
+    let tok = dev.launch_kernel(kern, (vec.len(),), &[&gate]).unwrap();
+    let kept = bundle.instantiate_kernel("lol", (&vec,)).unwrap();
+    match vec.try_reserve(vec.capacity()) {
+        Err(SvmVecFailure::ReferencedByPendingLaunch) => (),
+        _ => panic!("Vector was reallocated under a pending launch")
+    }
+    gate.complete().unwrap();
+    tok.await_completion().unwrap();
+    // the refused reserve left earlier bindings valid
+    dev.launch_kernel(kept, (vec.len(),), &[&tok]).unwrap().await_completion().unwrap();
+    while vec.has_pending_launches() {}
+
+    let stale = bundle.instantiate_kernel("lol", (&vec,)).unwrap();
+    vec.reserve(vec.capacity());
+    match dev.launch_kernel(stale, (vec.len(),), &[]) {
+        Err(crate::KernelLaunchFailure::ArgumentReleased) => (),
+        _ => panic!("Kernel launched with a moved vector")
+    }
+
+    let mut ix = 0;
+    for i in vec.iter() {
+        assert!(*i == ix * 4);
+        ix += 1;
+    }
+}
@@ -0,0 +1,116 @@
+use core::ptr::{null, null_mut};
+
+use cl_sys::{clEnqueueBarrierWithWaitList, clEnqueueMarkerWithWaitList, clFinish, clFlush, cl_command_queue, cl_event, cl_int, cl_uint};
+
+use crate::{trace, Device, OCLFailure, Token};
+
+#[derive(Debug, Clone, Copy)]
+pub enum SyncPointFailure {
+    ResourcesExhausted,
+    InvalidDependencies
+}
+
+type EnqueueFn = unsafe extern "system" fn(cl_command_queue, cl_uint, *const cl_event, *mut cl_event) -> cl_int;
+
+pub(crate) fn queue_op(
+    queue: cl_command_queue,
+    op: unsafe extern "system" fn(cl_command_queue) -> cl_int
+) -> Result<(), OCLFailure> { unsafe {
+    match op(queue) {
+        cl_sys::CL_SUCCESS => (),
+        cl_sys::CL_OUT_OF_RESOURCES |
+        cl_sys::CL_OUT_OF_HOST_MEMORY => {
+            return Err(OCLFailure::ResourcesExhausted)
+        },
+        cl_sys::CL_INVALID_COMMAND_QUEUE |
+        _ => unreachable!()
+    }
+    return Ok(());
+} }
+
+impl Device {
+    fn enqueue_sync_point(
+        &self,
+        enqueue: EnqueueFn,
+        name: &'static str,
+        dependencies: &[&Token]
+    ) -> Result<Token, SyncPointFailure> { unsafe {
+        let deps = dependencies.iter().map(|t| t.0.token).collect::<Vec<_>>();
+        let (deps_ptr, deps_num) = if deps.is_empty() {
+            (null(), 0)
+        } else {
+            (deps.as_ptr(), deps.len() as u32)
+        };
+        let mut event = null_mut();
+        let ret_code = enqueue(
+            self.ext.command_queue,
+            deps_num,
+            deps_ptr,
+            &mut event
+        );
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                return Err(SyncPointFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_EVENT_WAIT_LIST => {
+                return Err(SyncPointFailure::InvalidDependencies)
+            },
+            cl_sys::CL_INVALID_COMMAND_QUEUE |
+            _ => unreachable!()
+        }
+        trace::record_command(self.ext.command_queue, event, name, "sync", &deps);
+        return Ok(Token::from_event(event));
+    } }
+    pub fn enqueue_marker(&self, dependencies: &[&Token]) -> Result<Token, SyncPointFailure> {
+        self.enqueue_sync_point(clEnqueueMarkerWithWaitList, "marker", dependencies)
+    }
+    pub fn enqueue_barrier(&self, dependencies: &[&Token]) -> Result<Token, SyncPointFailure> {
+        self.enqueue_sync_point(clEnqueueBarrierWithWaitList, "barrier", dependencies)
+    }
+    pub fn flush(&self) -> Result<(), OCLFailure> {
+        queue_op(self.ext.command_queue, clFlush)
+    }
+    pub fn finish(&self) -> Result<(), OCLFailure> {
+        queue_op(self.ext.command_queue, clFinish)
+    }
+}
+
+#[test]
+fn fan_in() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let item_count = 65535;
+    let mem1 = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let mem2 = dev.allocate_buffer::<u32>(item_count).unwrap();
+
+    let text = r#"
+    __kernel void fill(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] = gix;
+    }
+    __kernel void sum(__global uint* param1, __global uint* param2) {
+        uint gix = get_global_id(0);
+        param1[gix] += param2[gix];
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let tok1 = dev.launch_kernel(bundle.instantiate_kernel("fill", (mem1,)).unwrap(), (item_count,), &[]).unwrap();
+    let tok2 = dev.launch_kernel(bundle.instantiate_kernel("fill", (mem2,)).unwrap(), (item_count,), &[]).unwrap();
+    let joined = dev.enqueue_marker(&[&tok1, &tok2]).unwrap();
+    let summed = dev.launch_kernel(bundle.instantiate_kernel("sum", (mem1, mem2,)).unwrap(), (item_count,), &[&joined]).unwrap();
+    let fence = dev.enqueue_barrier(&[]).unwrap();
+    dev.flush().unwrap();
+    fence.await_completion().unwrap();
+    assert!(matches!(summed.get_execution_state(), Ok(crate::ExecutionState::Complete)));
+
+    for (ix, i) in mem1.as_items().iter().enumerate() {
+        assert!(*i == ix as u32 * 2);
+    }
+    dev.finish().unwrap();
+    dev.deallocate_memory(mem1);
+    dev.deallocate_memory(mem2);
+}
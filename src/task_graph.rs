@@ -0,0 +1,335 @@
+use core::mem::size_of;
+use std::{collections::HashMap, sync::Arc};
+
+use cl_sys::{c_void, size_t};
+
+use crate::{allocation_tracker, command_graph::{copies_overlap, enqueue_svm_copy}, Binding, Device, EnqueueFailure, GridDimmensions, Kernel, KernelLaunchFailure, MemoryRef, SvmRegion, Token, UserToken};
+
+#[derive(Debug, Clone, Copy)]
+pub enum TaskGraphFailure {
+    NoDevices,
+    EmptyGraph,
+    ResourcesExhausted,
+    LaunchFailed(usize, KernelLaunchFailure),
+    DependencyFailed(usize),
+    LengthMismatch,
+    OverlappingCopy,
+    // an error code the driver is not documented to return
+    Rejected(i32),
+    // the task at this index is pinned past the given devices
+    UnknownDevice(usize),
+    // a buffer of the copy at this index was freed
+    BufferReleased(usize),
+    InvalidDependencies
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskId(usize);
+
+#[derive(Debug, Clone, Default)]
+pub struct TaskAccess {
+    reads: Vec<usize>,
+    writes: Vec<usize>
+}
+// Slices share the key of their allocation, so they conflict with
+// the whole of it and with each other
+fn access_key(region: &dyn SvmRegion) -> usize {
+    match region.tracker() {
+        Some(tracker) => Arc::as_ptr(&tracker) as usize,
+        None => region.svm_base_ptr() as usize,
+    }
+}
+impl TaskAccess {
+    pub fn new() -> TaskAccess {
+        TaskAccess::default()
+    }
+    pub fn reads(mut self, region: &dyn SvmRegion) -> TaskAccess {
+        self.reads.push(access_key(region));
+        self
+    }
+    pub fn writes(mut self, region: &dyn SvmRegion) -> TaskAccess {
+        self.writes.push(access_key(region));
+        self
+    }
+}
+
+enum TaskKind<'env> {
+    Launch {
+        kernel: Kernel,
+        grid_dim: u32,
+        dims: [size_t;3]
+    },
+    SvmCopy {
+        dst: *mut c_void,
+        src: *const c_void,
+        size: usize,
+        bindings: [Binding; 2]
+    },
+    Host(Box<dyn FnOnce() + 'env>)
+}
+struct Task<'env> {
+    kind: TaskKind<'env>,
+    deps: Vec<usize>,
+    device: Option<usize>
+}
+enum Issued {
+    Device(Token, usize),
+    Host(UserToken)
+}
+impl Issued {
+    fn token(&self) -> &Token {
+        match self {
+            Issued::Device(tok, _) => tok,
+            Issued::Host(tok) => tok,
+        }
+    }
+}
+
+pub struct TaskGraph<'env> {
+    tasks: Vec<Task<'env>>,
+    last_writer: HashMap<usize, usize>,
+    readers: HashMap<usize, Vec<usize>>
+}
+
+impl<'env> Default for TaskGraph<'env> {
+    fn default() -> Self {
+        TaskGraph::new()
+    }
+}
+impl<'env> TaskGraph<'env> {
+    pub fn new() -> TaskGraph<'env> {
+        TaskGraph {
+            tasks: Vec::new(),
+            last_writer: HashMap::new(),
+            readers: HashMap::new()
+        }
+    }
+    pub fn len(&self) -> usize { self.tasks.len() }
+    pub fn is_empty(&self) -> bool { self.tasks.is_empty() }
+    fn push_task(&mut self, kind: TaskKind<'env>, access: TaskAccess) -> TaskId {
+        let ix = self.tasks.len();
+        let mut deps = Vec::new();
+        for buffer in &access.reads {
+            if let Some(writer) = self.last_writer.get(buffer) {
+                deps.push(*writer);
+            }
+        }
+        for buffer in &access.writes {
+            if let Some(writer) = self.last_writer.get(buffer) {
+                deps.push(*writer);
+            }
+            if let Some(readers) = self.readers.remove(buffer) {
+                deps.extend(readers);
+            }
+        }
+        deps.sort_unstable();
+        deps.dedup();
+        deps.retain(|d| *d != ix);
+        for buffer in &access.reads {
+            if !access.writes.contains(buffer) {
+                self.readers.entry(*buffer).or_default().push(ix);
+            }
+        }
+        for buffer in &access.writes {
+            self.last_writer.insert(*buffer, ix);
+        }
+        self.tasks.push(Task { kind: kind, deps: deps, device: None });
+        return TaskId(ix);
+    }
+    pub fn add_launch(
+        &mut self,
+        kernel: Kernel,
+        grid_dimmensions: impl GridDimmensions,
+        access: TaskAccess
+    ) -> TaskId {
+        let kind = TaskKind::Launch {
+            kernel: kernel,
+            grid_dim: grid_dimmensions.dims(),
+            dims: grid_dimmensions.as_components()
+        };
+        self.push_task(kind, access)
+    }
+    pub fn add_svm_copy<T>(&mut self, src: MemoryRef<T>, dst: MemoryRef<T>) -> Result<TaskId, TaskGraphFailure> {
+        if src.len() != dst.len() {
+            return Err(TaskGraphFailure::LengthMismatch);
+        }
+        let size = src.len() * size_of::<T>();
+        if copies_overlap(src.ptr, dst.ptr, size) {
+            return Err(TaskGraphFailure::OverlappingCopy);
+        }
+        let (Some(src_tracker), Some(dst_tracker)) = (allocation_tracker(src.ptr), allocation_tracker(dst.ptr)) else {
+            return Err(TaskGraphFailure::BufferReleased(self.tasks.len()));
+        };
+        let kind = TaskKind::SvmCopy {
+            dst: dst.ptr,
+            src: src.ptr,
+            size: size,
+            bindings: [Binding::new(src_tracker, false), Binding::new(dst_tracker, true)]
+        };
+        let access = TaskAccess::new().reads(&src).writes(&dst);
+        return Ok(self.push_task(kind, access));
+    }
+    pub fn add_host_task(&mut self, task: impl FnOnce() + 'env, access: TaskAccess) -> TaskId {
+        self.push_task(TaskKind::Host(Box::new(task)), access)
+    }
+    pub fn pin(&mut self, task: TaskId, device_ix: usize) {
+        self.tasks[task.0].device = Some(device_ix);
+    }
+    pub fn dependencies_of(&self, task: TaskId) -> impl Iterator<Item = TaskId> + '_ {
+        self.tasks[task.0].deps.iter().map(|d| TaskId(*d))
+    }
+    pub fn execute(self, devices: &[Device]) -> Result<Token, TaskGraphFailure> {
+        if self.tasks.is_empty() {
+            return Err(TaskGraphFailure::EmptyGraph);
+        }
+        if devices.is_empty() {
+            return Err(TaskGraphFailure::NoDevices);
+        }
+        // checked up front so nothing is issued for a graph that cannot run
+        if let Some(ix) = self.tasks.iter().position(|t| t.device.is_some_and(|d| d >= devices.len())) {
+            return Err(TaskGraphFailure::UnknownDevice(ix));
+        }
+        let mut load = vec![0usize; devices.len()];
+        let mut issued: Vec<Issued> = Vec::new();
+        issued.reserve(self.tasks.len());
+        let mut host_tasks = Vec::new();
+        for (ix, task) in self.tasks.into_iter().enumerate() {
+            let deps = task.deps.iter().map(|d| issued[*d].token()).collect::<Vec<_>>();
+            let work = match &task.kind {
+                TaskKind::Launch { dims, grid_dim, .. } => dims[..*grid_dim as usize].iter().product(),
+                TaskKind::SvmCopy { .. } => 1,
+                TaskKind::Host(_) => 0,
+            };
+            let followed = task.deps.iter().rev().find_map(|d| match &issued[*d] {
+                Issued::Device(_, dev_ix) => Some(*dev_ix),
+                Issued::Host(_) => None,
+            });
+            let least_loaded = (0..devices.len()).min_by_key(|d| load[*d]).unwrap();
+            let dev_ix = task.device.or(followed).unwrap_or(least_loaded);
+            let device = &devices[dev_ix];
+            let queue = device.ext.command_queue;
+            let outcome = match task.kind {
+                TaskKind::Launch { kernel, grid_dim, dims } => {
+                    match device.enqueue_kernel_ref(queue, &kernel, grid_dim, dims, &deps) {
+                        Ok(tok) => Issued::Device(tok, dev_ix),
+                        Err(err) => return Err(TaskGraphFailure::LaunchFailed(ix, err)),
+                    }
+                },
+                TaskKind::SvmCopy { dst, src, size, bindings } => {
+                    match enqueue_svm_copy(queue, dst, src, size, &bindings, &deps) {
+                        Ok(tok) => Issued::Device(tok, dev_ix),
+                        Err(EnqueueFailure::Released) => return Err(TaskGraphFailure::BufferReleased(ix)),
+                        Err(EnqueueFailure::ResourcesExhausted) |
+                        Err(EnqueueFailure::Rejected(cl_sys::CL_OUT_OF_RESOURCES)) |
+                        Err(EnqueueFailure::Rejected(cl_sys::CL_OUT_OF_HOST_MEMORY)) => {
+                            return Err(TaskGraphFailure::ResourcesExhausted)
+                        },
+                        Err(EnqueueFailure::Rejected(cl_sys::CL_INVALID_EVENT_WAIT_LIST)) => {
+                            return Err(TaskGraphFailure::InvalidDependencies)
+                        },
+                        Err(EnqueueFailure::Rejected(cl_sys::CL_MEM_COPY_OVERLAP)) => {
+                            return Err(TaskGraphFailure::OverlappingCopy)
+                        },
+                        Err(EnqueueFailure::Rejected(ret_code)) => return Err(TaskGraphFailure::Rejected(ret_code)),
+                    }
+                },
+                TaskKind::Host(closure) => {
+                    // stands in for the closure until it has run
+                    let gate = match UserToken::new() {
+                        Ok(gate) => gate,
+                        Err(_) => return Err(TaskGraphFailure::ResourcesExhausted),
+                    };
+                    host_tasks.push((ix, task.deps, closure));
+                    Issued::Host(gate)
+                },
+            };
+            if let Issued::Device(..) = outcome {
+                load[dev_ix] += work;
+            }
+            issued.push(outcome);
+        }
+        // device work gated on host tasks is already queued, so flush it
+        // before blocking on anything
+        for device in devices {
+            let _ = device.flush();
+        }
+        for (ix, deps, closure) in host_tasks {
+            let deps = deps.iter().map(|d| issued[*d].token()).collect::<Vec<_>>();
+            if Token::wait_all(&deps, None).is_err() {
+                // unresolved gates fail their dependents when dropped
+                return Err(TaskGraphFailure::DependencyFailed(ix));
+            }
+            closure();
+            let Issued::Host(gate) = &issued[ix] else { unreachable!() };
+            if gate.complete().is_err() {
+                return Err(TaskGraphFailure::ResourcesExhausted);
+            }
+        }
+        let all = issued.iter().map(|i| i.token()).collect::<Vec<_>>();
+        match devices[0].enqueue_marker(&all) {
+            Ok(tok) => return Ok(tok),
+            Err(_) => return Err(TaskGraphFailure::ResourcesExhausted),
+        }
+    }
+}
+
+#[test]
+fn inferred_order() {
+    let devs = crate::enumerate_devices().unwrap();
+
+    let item_count = 4096;
+    let dev = &devs[0];
+    let a = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let b = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let text = r#"
+    __kernel void fill(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] = gix;
+    }
+    __kernel void double_up(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+
+    let mut host_sum = 0u64;
+    let mut graph = TaskGraph::new();
+    let fill = graph.add_launch(bundle.instantiate_kernel("fill", (a,)).unwrap(), (item_count,), TaskAccess::new().writes(&a));
+    let copy = graph.add_svm_copy(a, b).unwrap();
+    assert!(matches!(graph.add_svm_copy(a, b.slice(0 .. 1)), Err(TaskGraphFailure::LengthMismatch)));
+    assert!(matches!(graph.add_svm_copy(a, a), Err(TaskGraphFailure::OverlappingCopy)));
+    let double = graph.add_launch(bundle.instantiate_kernel("double_up", (a,)).unwrap(), (item_count,), TaskAccess::new().reads(&a).writes(&a));
+    let sum = graph.add_host_task(|| {
+        host_sum = b.as_items().iter().map(|i| *i as u64).sum();
+    }, TaskAccess::new().reads(&b));
+    let again = graph.add_launch(bundle.instantiate_kernel("double_up", (b,)).unwrap(), (item_count,), TaskAccess::new().reads(&b).writes(&b));
+
+    assert!(graph.dependencies_of(fill).count() == 0);
+    assert!(graph.dependencies_of(copy).eq([fill]));
+    // writes after the copy read `a`
+    assert!(graph.dependencies_of(double).eq([fill, copy]));
+    assert!(graph.dependencies_of(sum).eq([copy]));
+    assert!(graph.dependencies_of(again).eq([copy, sum]));
+
+    graph.execute(&devs).unwrap().await_completion().unwrap();
+    let mut pinned = TaskGraph::new();
+    let task = pinned.add_launch(bundle.instantiate_kernel("fill", (a,)).unwrap(), (item_count,), TaskAccess::new().writes(&a));
+    pinned.pin(task, devs.len());
+    // a slice conflicts with the buffer it was taken from
+    let head = a.slice(0 .. 16);
+    let partial = pinned.add_launch(bundle.instantiate_kernel("double_up", (head,)).unwrap(), (16,), TaskAccess::new().reads(&head).writes(&head));
+    assert!(pinned.dependencies_of(partial).eq([task]));
+    assert!(matches!(pinned.execute(&devs), Err(TaskGraphFailure::UnknownDevice(0))));
+    let expected = (0..item_count as u64).sum::<u64>();
+    assert!(host_sum == expected);
+    for (ix, i) in a.as_items().iter().enumerate() {
+        assert!(*i == ix as u32 * 2);
+    }
+    for (ix, i) in b.as_items().iter().enumerate() {
+        assert!(*i == ix as u32 * 2);
+    }
+    dev.deallocate_memory(a);
+    dev.deallocate_memory(b);
+}
@@ -0,0 +1,163 @@
+use core::{future::Future, mem::transmute, pin::Pin, sync::atomic::{AtomicI32, Ordering}, task::{Context, Poll, Waker}};
+use std::sync::{Arc, Mutex};
+
+use cl_sys::{c_void, clSetEventCallback, cl_event, cl_int, CL_COMPLETE};
+
+use crate::{CompletionAwaitFailure, Token};
+
+const PENDING: i32 = 1;
+
+// Shared between a token and the completion callback of its event
+pub(crate) struct WakerSlot {
+    status: AtomicI32,
+    waker: Mutex<Option<Waker>>
+}
+
+impl Token {
+    fn poll_completion(&self, cx: &mut Context<'_>) -> Poll<Result<(), CompletionAwaitFailure>> { unsafe {
+        let this = &self.0;
+        let mut waker_slot = this.waker_slot.lock().unwrap();
+        let slot = match &*waker_slot {
+            Some(slot) => slot.clone(),
+            None => {
+                let slot = Arc::new(WakerSlot {
+                    status: AtomicI32::new(PENDING),
+                    waker: Mutex::new(None)
+                });
+                unsafe extern "C" fn invoker(_:cl_event, code:cl_int, ud: *mut ()) {
+                    let slot = Arc::from_raw(ud.cast::<WakerSlot>());
+                    let mut waker = slot.waker.lock().unwrap();
+                    slot.status.store(code, Ordering::Release);
+                    if let Some(waker) = waker.take() {
+                        waker.wake();
+                    }
+                }
+                type ClCallback = extern "C" fn(cl_event, cl_int, *mut c_void) ;
+                let fptr = transmute::<_, ClCallback>(invoker as *mut ());
+                let user_data = Arc::into_raw(slot.clone());
+                let ret_code = clSetEventCallback(
+                    this.token,
+                    CL_COMPLETE,
+                    Some(fptr),
+                    user_data.cast_mut().cast()
+                );
+                match ret_code {
+                    cl_sys::CL_SUCCESS => (),
+                    cl_sys::CL_OUT_OF_RESOURCES |
+                    cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                        drop(Arc::from_raw(user_data));
+                        return Poll::Ready(Err(CompletionAwaitFailure::NoMem))
+                    },
+                    _ => unreachable!()
+                }
+                *waker_slot = Some(slot.clone());
+                slot
+            }
+        };
+        drop(waker_slot);
+        let mut waker = slot.waker.lock().unwrap();
+        match slot.status.load(Ordering::Acquire) {
+            PENDING => (),
+            cl_sys::CL_COMPLETE => return Poll::Ready(Ok(())),
+            _ => return Poll::Ready(Err(CompletionAwaitFailure::JobFinishedWithError)),
+        }
+        match &mut *waker {
+            Some(known) if known.will_wake(cx.waker()) => (),
+            _ => *waker = Some(cx.waker().clone()),
+        }
+        return Poll::Pending;
+    } }
+}
+impl Future for Token {
+    type Output = Result<(), CompletionAwaitFailure>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.poll_completion(cx)
+    }
+}
+impl<'a> Future for &'a Token {
+    type Output = Result<(), CompletionAwaitFailure>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.poll_completion(cx)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Token {
+    pub async fn completion_within_tokio(
+        &self,
+        timeout: core::time::Duration
+    ) -> Result<(), CompletionAwaitFailure> {
+        match tokio::time::timeout(timeout, self).await {
+            Ok(outcome) => outcome,
+            Err(_) => Err(CompletionAwaitFailure::TimedOut),
+        }
+    }
+}
+#[cfg(feature = "smol")]
+impl Token {
+    pub async fn completion_within_smol(
+        &self,
+        timeout: core::time::Duration
+    ) -> Result<(), CompletionAwaitFailure> {
+        let timer = async {
+            smol::Timer::after(timeout).await;
+            Err(CompletionAwaitFailure::TimedOut)
+        };
+        smol::future::or(self, timer).await
+    }
+}
+
+#[cfg(test)]
+fn block_on<F: Future>(future: F) -> F::Output {
+    use std::task::Wake;
+    struct ThreadWaker(std::thread::Thread);
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = core::pin::pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(val) => return val,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[test]
+fn await_token() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let item_count = 65535;
+    let mut mem = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let mut ix = 0;
+    for item in mem.as_mut_items() {
+        *item = ix;
+        ix += 1;
+    }
+
+    let text = r#"
+    __kernel void lol(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let kern = bundle.instantiate_kernel("lol", (mem,)).unwrap();
+    let tok = dev.launch_kernel(kern, (item_count,), &[]).unwrap();
+
+    block_on(&tok).unwrap();
+    block_on(tok).unwrap();
+
+    let mut ix = 0;
+    for i in mem.as_items() {
+        assert!(*i == ix * 2);
+        ix += 1;
+    }
+    dev.deallocate_memory(mem);
+}
@@ -0,0 +1,166 @@
+use core::{ptr::null, sync::atomic::{AtomicI32, Ordering}, time::Duration};
+use std::{os::fd::AsRawFd, time::Instant};
+
+use crate::{CompletionAwaitFailure, Token};
+
+// Futex values below 1 are final event statuses, see `Token::as_futex`
+const CALLBACK_ARMED: i32 = 2;
+
+fn remaining(deadline: Option<Instant>) -> Result<Option<Duration>, CompletionAwaitFailure> {
+    match deadline {
+        None => Ok(None),
+        Some(deadline) => {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(CompletionAwaitFailure::TimedOut)
+            }
+            Ok(Some(left))
+        }
+    }
+}
+
+impl Token {
+    pub fn await_completion_on_token_futex_within(
+        token_futex: &AtomicI32,
+        timeout: Option<Duration>
+    ) -> Result<(), CompletionAwaitFailure> { unsafe {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            match token_futex.load(Ordering::Acquire) {
+                CALLBACK_ARMED => (),
+                cl_sys::CL_COMPLETE => return Ok(()),
+                _ => return Err(CompletionAwaitFailure::JobFinishedWithError),
+            }
+            let spec = match remaining(deadline)? {
+                Some(left) => Some(libc::timespec {
+                    tv_sec: left.as_secs() as _,
+                    tv_nsec: left.subsec_nanos() as _
+                }),
+                None => None,
+            };
+            let spec_ptr = match &spec {
+                Some(spec) => spec as *const libc::timespec,
+                None => null(),
+            };
+            let _ = libc::syscall(
+                libc::SYS_futex,
+                token_futex,
+                libc::FUTEX_WAIT,
+                CALLBACK_ARMED,
+                spec_ptr,
+                0,
+                0
+            );
+        }
+    } }
+    pub fn await_completion_within(
+        &self,
+        timeout: Option<Duration>
+    ) -> Result<(), CompletionAwaitFailure> {
+        let futex = match self.as_futex() {
+            Ok(futex) => futex,
+            Err(_) => return Err(CompletionAwaitFailure::NoMem),
+        };
+        Token::await_completion_on_token_futex_within(futex, timeout)
+    }
+    pub fn wait_all(
+        tokens: &[&Token],
+        timeout: Option<Duration>
+    ) -> Result<(), CompletionAwaitFailure> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut futexes = Vec::new();
+        futexes.reserve(tokens.len());
+        for tok in tokens {
+            match tok.as_futex() {
+                Ok(futex) => futexes.push(futex),
+                Err(_) => return Err(CompletionAwaitFailure::NoMem),
+            }
+        }
+        for futex in futexes {
+            let left = match deadline {
+                Some(deadline) => Some(deadline.saturating_duration_since(Instant::now())),
+                None => None,
+            };
+            Token::await_completion_on_token_futex_within(futex, left)?;
+        }
+        return Ok(());
+    }
+    pub fn wait_any(
+        tokens: &[&Token],
+        timeout: Option<Duration>
+    ) -> Result<usize, CompletionAwaitFailure> { unsafe {
+        if tokens.is_empty() {
+            return Err(CompletionAwaitFailure::NoTokens);
+        }
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut polled = Vec::new();
+        polled.reserve(tokens.len());
+        for tok in tokens {
+            let fd = match tok.pollable() {
+                Ok(fd) => fd,
+                Err(_) => return Err(CompletionAwaitFailure::NoMem),
+            };
+            // the token keeps its descriptor open
+            polled.push(libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 });
+        }
+        // First pass only checks, so finished tokens are seen even
+        // when the deadline has already passed
+        let mut wait_ms = 0;
+        loop {
+            let ret_code = libc::poll(polled.as_mut_ptr(), polled.len() as _, wait_ms);
+            if ret_code == -1 {
+                match *libc::__errno_location() {
+                    libc::EINTR => (),
+                    libc::ENOMEM => return Err(CompletionAwaitFailure::NoMem),
+                    libc::EFAULT |
+                    libc::EINVAL |
+                    _ => unreachable!()
+                }
+            } else if let Some(ix) = polled.iter().position(|p| p.revents & libc::POLLIN != 0) {
+                return Ok(ix);
+            }
+            wait_ms = match remaining(deadline)? {
+                Some(left) => left.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+                None => -1,
+            };
+        }
+    } }
+}
+
+#[test]
+fn many_tokens() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let item_count = 65535;
+    let mem1 = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let mem2 = dev.allocate_buffer::<u32>(item_count).unwrap();
+
+    let text = r#"
+    __kernel void lol(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] = gix;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let kern1 = bundle.instantiate_kernel("lol", (mem1,)).unwrap();
+    let kern2 = bundle.instantiate_kernel("lol", (mem2,)).unwrap();
+    let tok1 = dev.launch_kernel(kern1, (item_count,), &[]).unwrap();
+    let tok2 = dev.launch_kernel(kern2, (item_count,), &[]).unwrap();
+
+    let timeout = Some(Duration::from_secs(10));
+    let ix = Token::wait_any(&[&tok1, &tok2], timeout).unwrap();
+    assert!(ix < 2);
+    Token::wait_all(&[&tok1, &tok2], timeout).unwrap();
+    tok1.await_completion_within(Some(Duration::from_millis(1))).unwrap();
+    Token::wait_any(&[&tok1, &tok2], Some(Duration::ZERO)).unwrap();
+    assert!(matches!(Token::wait_any(&[], timeout), Err(CompletionAwaitFailure::NoTokens)));
+
+    for mem in [mem1, mem2] {
+        for (ix, i) in mem.as_items().iter().enumerate() {
+            assert!(*i == ix as u32);
+        }
+        dev.deallocate_memory(mem);
+    }
+}
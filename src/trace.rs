@@ -0,0 +1,342 @@
+use core::{ptr::null_mut, sync::atomic::{AtomicBool, Ordering}, time::Duration};
+use std::{collections::HashMap, io::{self, Write}, sync::{Arc, Mutex}, time::Instant};
+
+use cl_sys::{clGetKernelInfo, clRetainEvent, cl_command_queue, cl_event, cl_kernel, CL_KERNEL_FUNCTION_NAME};
+
+use crate::Token;
+#[cfg(feature = "opencl_2_1")]
+use crate::profiling::device_and_host_timer;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static RECORDER: Mutex<Option<Arc<TraceRecorder>>> = Mutex::new(None);
+
+struct RecordedCommand {
+    token: Token,
+    queue: usize,
+    name: String,
+    category: &'static str,
+    grid: Option<Vec<usize>>,
+    deps: Vec<usize>,
+    clock: Option<ClockSync>,
+    // host time of enqueueing, stands in for commands without device times
+    recorded: Duration
+}
+// A device timestamp taken together with the host clock, so device
+// times of the command can be placed on the recorder timeline
+#[derive(Clone, Copy)]
+struct ClockSync {
+    device: Duration,
+    since_origin: Duration
+}
+struct RecordedBuild {
+    start: Duration,
+    duration: Duration,
+    kernels: String
+}
+
+// Commands of queues not in profiling mode show up as instants
+// at the time they were enqueued
+pub struct TraceRecorder {
+    origin: Instant,
+    commands: Mutex<Vec<RecordedCommand>>,
+    builds: Mutex<Vec<RecordedBuild>>,
+    #[cfg(feature = "opencl_2_1")]
+    clocks: Mutex<HashMap<usize, Option<ClockSync>>>
+}
+
+impl TraceRecorder {
+    pub fn start() -> Arc<TraceRecorder> {
+        let recorder = Arc::new(TraceRecorder {
+            origin: Instant::now(),
+            commands: Mutex::new(Vec::new()),
+            builds: Mutex::new(Vec::new()),
+            #[cfg(feature = "opencl_2_1")]
+            clocks: Mutex::new(HashMap::new())
+        });
+        *RECORDER.lock().unwrap() = Some(recorder.clone());
+        ACTIVE.store(true, Ordering::Release);
+        return recorder;
+    }
+    pub fn stop(self: &Arc<Self>) {
+        let mut active = RECORDER.lock().unwrap();
+        if let Some(current) = &*active {
+            if Arc::ptr_eq(current, self) {
+                *active = None;
+                ACTIVE.store(false, Ordering::Release);
+            }
+        }
+    }
+    pub fn write_chrome_trace(&self, out: &mut impl Write) -> io::Result<()> {
+        let commands = self.commands.lock().unwrap();
+        let builds = self.builds.lock().unwrap();
+        let profiles = commands.iter().map(|c| c.token.profile().ok()).collect::<Vec<_>>();
+        // Without a clock sync device times only line up with each other
+        let device_origin = profiles.iter().flatten().map(|p| p.queued).min().unwrap_or_default();
+        let to_us = |time: Duration, clock: Option<ClockSync>| match clock {
+            Some(clock) => {
+                let ns = clock.since_origin.as_nanos() as i128 + time.as_nanos() as i128 - clock.device.as_nanos() as i128;
+                ns as f64 / 1000.0
+            },
+            None => time.saturating_sub(device_origin).as_nanos() as f64 / 1000.0,
+        };
+        let mut tracks = HashMap::new();
+        for cmd in commands.iter() {
+            let next = tracks.len();
+            tracks.entry(cmd.queue).or_insert(next);
+        }
+
+        let mut events = Vec::new();
+        events.push(String::from(r#"{"name":"process_name","ph":"M","pid":0,"args":{"name":"host"}}"#));
+        events.push(String::from(r#"{"name":"process_name","ph":"M","pid":1,"args":{"name":"device queues"}}"#));
+        for (queue, tid) in &tracks {
+            events.push(format!(
+                r#"{{"name":"thread_name","ph":"M","pid":1,"tid":{},"args":{{"name":"queue {:#x}"}}}}"#,
+                tid, queue
+            ));
+        }
+        for build in builds.iter() {
+            events.push(format!(
+                r#"{{"name":"build","cat":"build","ph":"X","pid":0,"tid":0,"ts":{:.3},"dur":{:.3},"args":{{"kernels":"{}"}}}}"#,
+                build.start.as_nanos() as f64 / 1000.0,
+                build.duration.as_nanos() as f64 / 1000.0,
+                escape(&build.kernels)
+            ));
+        }
+        // start and end of every command, in microseconds
+        let spans = commands.iter().zip(&profiles).map(|(cmd, profile)| match profile {
+            Some(profile) => (to_us(profile.started, cmd.clock), to_us(profile.ended, cmd.clock)),
+            None => (cmd.recorded.as_nanos() as f64 / 1000.0, cmd.recorded.as_nanos() as f64 / 1000.0),
+        }).collect::<Vec<_>>();
+        let mut flow_id = 0;
+        for (ix, cmd) in commands.iter().enumerate() {
+            let tid = tracks[&cmd.queue];
+            let grid = match &cmd.grid {
+                Some(grid) => format!(r#","grid":{:?}"#, grid),
+                None => String::new(),
+            };
+            let Some(profile) = profiles[ix] else {
+                events.push(format!(
+                    r#"{{"name":"{}","cat":"{}","ph":"i","s":"t","pid":1,"tid":{},"ts":{:.3},"args":{{"id":{},"deps":{:?},"profiled":false{}}}}}"#,
+                    escape(&cmd.name),
+                    cmd.category,
+                    tid,
+                    spans[ix].0,
+                    ix,
+                    cmd.deps,
+                    grid
+                ));
+                continue;
+            };
+            events.push(format!(
+                r#"{{"name":"{}","cat":"{}","ph":"X","pid":1,"tid":{},"ts":{:.3},"dur":{:.3},"args":{{"id":{},"deps":{:?},"queued_us":{:.3},"submitted_us":{:.3}{}}}}}"#,
+                escape(&cmd.name),
+                cmd.category,
+                tid,
+                spans[ix].0,
+                profile.execution_time().as_nanos() as f64 / 1000.0,
+                ix,
+                cmd.deps,
+                to_us(profile.queued, cmd.clock),
+                to_us(profile.submitted, cmd.clock),
+                grid
+            ));
+            for dep in &cmd.deps {
+                let dep_tid = tracks[&commands[*dep].queue];
+                events.push(format!(
+                    r#"{{"name":"dependency","cat":"dependency","ph":"s","id":{},"pid":1,"tid":{},"ts":{:.3}}}"#,
+                    flow_id, dep_tid, spans[*dep].1
+                ));
+                events.push(format!(
+                    r#"{{"name":"dependency","cat":"dependency","ph":"f","bp":"e","id":{},"pid":1,"tid":{},"ts":{:.3}}}"#,
+                    flow_id, tid, spans[ix].0
+                ));
+                flow_id += 1;
+            }
+        }
+
+        writeln!(out, r#"{{"displayTimeUnit":"ns","traceEvents":["#)?;
+        for (ix, event) in events.iter().enumerate() {
+            let sep = if ix + 1 == events.len() { "" } else { "," };
+            writeln!(out, "{}{}", event, sep)?;
+        }
+        writeln!(out, "]}}")?;
+        return Ok(());
+    }
+    #[cfg(feature = "opencl_2_1")]
+    fn clock_of(&self, queue: cl_command_queue) -> Option<ClockSync> { unsafe {
+        let mut device: cl_sys::cl_device_id = null_mut();
+        let ret_code = cl_sys::clGetCommandQueueInfo(
+            queue,
+            cl_sys::CL_QUEUE_DEVICE,
+            core::mem::size_of::<cl_sys::cl_device_id>(),
+            core::ptr::addr_of_mut!(device).cast(),
+            null_mut()
+        );
+        if ret_code != cl_sys::CL_SUCCESS {
+            return None;
+        }
+        let mut clocks = self.clocks.lock().unwrap();
+        let clock = clocks.entry(device as usize).or_insert_with(|| {
+            let sync = device_and_host_timer(device).ok()?;
+            let clock = ClockSync {
+                device: sync.device,
+                since_origin: self.origin.elapsed()
+            };
+            Some(clock)
+        });
+        return *clock;
+    } }
+    #[cfg(not(feature = "opencl_2_1"))]
+    fn clock_of(&self, _queue: cl_command_queue) -> Option<ClockSync> {
+        None
+    }
+    fn push_command(&self, mut cmd: RecordedCommand, deps: &[cl_event]) {
+        cmd.clock = self.clock_of(cmd.queue as cl_command_queue);
+        cmd.recorded = self.origin.elapsed();
+        let mut commands = self.commands.lock().unwrap();
+        for dep in deps {
+            let known = commands.iter().position(|c| c.token.0.token == *dep);
+            if let Some(ix) = known {
+                cmd.deps.push(ix);
+            }
+        }
+        commands.push(cmd);
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+fn active_recorder() -> Option<Arc<TraceRecorder>> {
+    if !ACTIVE.load(Ordering::Acquire) {
+        return None;
+    }
+    RECORDER.lock().unwrap().clone()
+}
+pub(crate) fn event_slot(event: &mut cl_event) -> *mut cl_event {
+    if ACTIVE.load(Ordering::Acquire) { event } else { null_mut() }
+}
+pub(crate) fn record_launch(
+    queue: cl_command_queue,
+    kernel: cl_kernel,
+    event: cl_event,
+    grid: &[usize],
+    deps: &[cl_event]
+) { unsafe {
+    let Some(recorder) = active_recorder() else { return };
+    let mut name = vec![0u8; 256];
+    let mut len = 0;
+    let ret_code = clGetKernelInfo(
+        kernel,
+        CL_KERNEL_FUNCTION_NAME,
+        name.len(),
+        name.as_mut_ptr().cast(),
+        &mut len
+    );
+    let name = match ret_code {
+        cl_sys::CL_SUCCESS => String::from_utf8_lossy(&name[..len.saturating_sub(1)]).into_owned(),
+        _ => String::from("kernel"),
+    };
+    let _ = clRetainEvent(event);
+    let cmd = RecordedCommand {
+        token: Token::from_event(event),
+        queue: queue as usize,
+        name: name,
+        category: "kernel",
+        grid: Some(grid.to_vec()),
+        deps: Vec::new(),
+        clock: None,
+        recorded: Duration::ZERO
+    };
+    recorder.push_command(cmd, deps);
+} }
+pub(crate) fn record_copy(queue: cl_command_queue, event: cl_event, name: &'static str) {
+    if event.is_null() {
+        return;
+    }
+    let token = Token::from_event(event);
+    let Some(recorder) = active_recorder() else { return };
+    let cmd = RecordedCommand {
+        token: token,
+        queue: queue as usize,
+        name: String::from(name),
+        category: "copy",
+        grid: None,
+        deps: Vec::new(),
+        clock: None,
+        recorded: Duration::ZERO
+    };
+    recorder.push_command(cmd, &[]);
+}
+// For commands that hand their event out as a token
+pub(crate) fn record_command(
+    queue: cl_command_queue,
+    event: cl_event,
+    name: &'static str,
+    category: &'static str,
+    deps: &[cl_event]
+) { unsafe {
+    let Some(recorder) = active_recorder() else { return };
+    let _ = clRetainEvent(event);
+    let cmd = RecordedCommand {
+        token: Token::from_event(event),
+        queue: queue as usize,
+        name: String::from(name),
+        category: category,
+        grid: None,
+        deps: Vec::new(),
+        clock: None,
+        recorded: Duration::ZERO
+    };
+    recorder.push_command(cmd, deps);
+} }
+pub(crate) fn record_build(started: Instant, kernel_names: &[u8]) {
+    let Some(recorder) = active_recorder() else { return };
+    let names = kernel_names.strip_suffix(&[0]).unwrap_or(kernel_names);
+    let build = RecordedBuild {
+        start: started.saturating_duration_since(recorder.origin),
+        duration: started.elapsed(),
+        kernels: String::from_utf8_lossy(names).into_owned()
+    };
+    recorder.builds.lock().unwrap().push(build);
+}
+
+#[test]
+fn chrome_trace() {
+    let mut devs = crate::enumerate_devices().unwrap();
+    let dev = &mut devs[0];
+    dev.set_profiling(true).unwrap();
+    let recorder = TraceRecorder::start();
+
+    let item_count = 4096;
+    let text = r#"
+    __kernel void fill(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] = gix;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let mem = dev.allocate_buffer::<u32>(item_count).unwrap();
+    let tok1 = dev.launch_kernel(bundle.instantiate_kernel("fill", (mem,)).unwrap(), (item_count,), &[]).unwrap();
+    let tok2 = dev.launch_kernel(bundle.instantiate_kernel("fill", (mem,)).unwrap(), (item_count,), &[&tok1]).unwrap();
+    tok2.await_completion().unwrap();
+    dev.set_profiling(false).unwrap();
+    dev.launch_kernel(bundle.instantiate_kernel("fill", (mem,)).unwrap(), (item_count,), &[]).unwrap().await_completion().unwrap();
+    dev.set_profiling(true).unwrap();
+    let buffer = dev.allocate_device_buffer::<u32>(item_count).unwrap();
+    dev.write_buffer(&buffer, mem.as_items()).unwrap();
+    dev.enqueue_marker(&[&tok2]).unwrap().await_completion().unwrap();
+    recorder.stop();
+
+    let mut json = Vec::new();
+    recorder.write_chrome_trace(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.matches(r#""name":"fill""#).count() == 3);
+    assert!(json.contains(r#""ph":"i""#));
+    assert!(json.contains(r#""name":"write_buffer""#));
+    assert!(json.contains(r#""cat":"build""#));
+    assert!(json.contains(r#""deps":[0]"#));
+    assert!(json.contains(r#""name":"marker","cat":"sync""#));
+    dev.deallocate_memory(mem);
+}
@@ -0,0 +1,118 @@
+use core::{ops::Deref, sync::atomic::{AtomicBool, Ordering}};
+
+use cl_sys::{clCreateUserEvent, clSetUserEventStatus, cl_int, CL_SUCCESS};
+
+use crate::{OCLFailure, Token, OCL_SHARED_CONTEXT};
+
+// Reported to dependents of a user token dropped without being resolved
+const ABANDONED_STATUS: cl_int = -1;
+
+#[derive(Debug, Clone, Copy)]
+pub enum UserTokenFailure {
+    ResourcesExhausted,
+    AlreadyResolved
+}
+
+pub struct UserToken {
+    token: Token,
+    resolved: AtomicBool
+}
+impl UserToken {
+    pub fn new() -> Result<UserToken, OCLFailure> { unsafe {
+        let ctx = OCL_SHARED_CONTEXT.get_ocl_context();
+        let mut ret_code = CL_SUCCESS;
+        let event = clCreateUserEvent(ctx, &mut ret_code);
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                return Err(OCLFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_CONTEXT |
+            _ => unreachable!()
+        }
+        let val = UserToken {
+            token: Token::from_event(event),
+            resolved: AtomicBool::new(false)
+        };
+        return Ok(val);
+    } }
+    fn resolve(&self, status: cl_int) -> Result<(), UserTokenFailure> { unsafe {
+        if self.resolved.swap(true, Ordering::AcqRel) {
+            return Err(UserTokenFailure::AlreadyResolved)
+        }
+        let ret_code = clSetUserEventStatus(self.token.0.token, status);
+        match ret_code {
+            cl_sys::CL_SUCCESS => (),
+            cl_sys::CL_OUT_OF_RESOURCES |
+            cl_sys::CL_OUT_OF_HOST_MEMORY => {
+                self.resolved.store(false, Ordering::Release);
+                return Err(UserTokenFailure::ResourcesExhausted)
+            },
+            cl_sys::CL_INVALID_OPERATION => {
+                return Err(UserTokenFailure::AlreadyResolved)
+            },
+            cl_sys::CL_INVALID_EVENT |
+            cl_sys::CL_INVALID_VALUE |
+            _ => unreachable!()
+        }
+        return Ok(());
+    } }
+    pub fn complete(&self) -> Result<(), UserTokenFailure> {
+        self.resolve(cl_sys::CL_COMPLETE)
+    }
+    pub fn fail(&self, status: i32) -> Result<(), UserTokenFailure> {
+        assert!(status < 0, "Failure status must be negative");
+        self.resolve(status)
+    }
+    pub fn is_resolved(&self) -> bool {
+        self.resolved.load(Ordering::Acquire)
+    }
+}
+impl Deref for UserToken {
+    type Target = Token;
+    fn deref(&self) -> &Token {
+        &self.token
+    }
+}
+impl Drop for UserToken {
+    fn drop(&mut self) {
+        if !self.is_resolved() {
+            let _ = self.resolve(ABANDONED_STATUS);
+        }
+    }
+}
+
+#[test]
+fn gated_launch() {
+    let devs = crate::enumerate_devices().unwrap();
+    let dev = &devs[0];
+
+    let item_count = 65535;
+    let mut mem = dev.allocate_buffer::<u32>(item_count).unwrap();
+    for item in mem.as_mut_items() {
+        *item = 1;
+    }
+
+    let text = r#"
+    __kernel void lol(__global uint* param1) {
+        uint gix = get_global_id(0);
+        param1[gix] *= 2;
+    }"#;
+    let bundle = crate::CodeBundle::from_text_bytes(&[
+        text.as_bytes()
+    ]).unwrap();
+    let gate = UserToken::new().unwrap();
+    let kern = bundle.instantiate_kernel("lol", (mem,)).unwrap();
+    let tok = dev.launch_kernel(kern, (item_count,), &[&gate]).unwrap();
+
+    let waited = tok.await_completion_within(Some(core::time::Duration::from_millis(50)));
+    assert!(matches!(waited, Err(crate::CompletionAwaitFailure::TimedOut)));
+    assert!(mem.as_items().iter().all(|i| *i == 1));
+
+    gate.complete().unwrap();
+    assert!(matches!(gate.complete(), Err(UserTokenFailure::AlreadyResolved)));
+    tok.await_completion().unwrap();
+    assert!(mem.as_items().iter().all(|i| *i == 2));
+    dev.deallocate_memory(mem);
+}
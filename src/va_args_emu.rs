@@ -1,11 +1,15 @@
 use core::{any::TypeId, mem::{align_of_val, forget, size_of_val, transmute}, ptr::{addr_of, drop_in_place}};
+use std::sync::Arc;
+
+use crate::BufferTracker;
 #[repr(C)]
 pub struct ErasedRef {
   pub data_ptr: *const u8,
   pub size: usize,
   pub alignment: usize,
   pub type_id: TypeId,
-  pub dctor: fn(*mut u8)
+  pub dctor: fn(*mut u8),
+  pub(crate) tracker: Option<Arc<BufferTracker>>
 }
 
 pub trait KernelArgument: Sized {
@@ -22,7 +26,8 @@ macro_rules! autoimpl {
                 size: size_of_val(self),
                 alignment: align_of_val(self),
                 type_id: TypeId::of::<Self>(),
-                dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())}
+                dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+                tracker: None
               }
           }
         }
@@ -43,6 +48,8 @@ autoimpl!(
 );
 
 pub(crate) struct SomePointer {}
+// data_ptr of such arguments is the SVM pointer itself
+pub(crate) struct SomeSvmPointer {}
 impl<T> KernelArgument for *mut T {
   fn as_opaque(&self) -> ErasedRef {
     ErasedRef {
@@ -50,7 +57,8 @@ impl<T> KernelArgument for *mut T {
       size: size_of_val(self),
       alignment: align_of_val(self),
       type_id: TypeId::of::<SomePointer>(),
-      dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())}
+      dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+      tracker: None
     }
   }
 }
@@ -61,7 +69,8 @@ impl KernelArgument for () {
       size: size_of_val(self),
       alignment: align_of_val(self),
       type_id: TypeId::of::<Self>(),
-      dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())}
+      dctor: unsafe{transmute(drop_in_place::<Self> as *mut ())},
+      tracker: None
     }
   }
 }